@@ -9,6 +9,8 @@ fn main() {
     let cfg = Config {
         entry_per_segment: 100,
         check_crc32: false,
+        sync: SyncPolicy::Never,
+        ..Default::default()
     };
 
     let mut wal = WAL::open("./testdir", cfg).unwrap();
@@ -27,8 +29,8 @@ fn main() {
     assert_eq!(data.len(), entry_num);
     assert_eq!(wal.len(), 0);
     for (i, v) in data.iter().enumerate() {
-        assert_eq!((i + 1) as usize, v.len());
-        assert_eq!(&buf[..(i + 1) as usize].to_vec(), v);
+        assert_eq!(i + 1, v.len());
+        assert_eq!(&buf[..i + 1].to_vec(), v);
     }
 
     fs::remove_dir_all("./testdir").unwrap();
@@ -1,9 +1,45 @@
+use std::time::Duration;
+
 /// WAL config
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
 pub struct Config {
     /// entry limit of a single segment file
     pub entry_per_segment: usize,
 
+    /// size limit of the entries in a single segment file, 0 for no limit
+    ///
+    /// An entry larger than the limit still gets a segment file of its own.
+    pub max_segment_bytes: usize,
+
+    /// size limit of a single entry, 0 for no limit
+    ///
+    /// Larger entries are rejected on write, and refused on read as corrupted.
+    pub max_entry_size: usize,
+
+    /// age limit of a single segment file, the next write after it goes to a new one
+    pub max_segment_age: Option<Duration>,
+
     /// if we should do check_sum
     pub check_crc32: bool,
+
+    /// when written entries should be flushed to disk
+    pub sync: SyncPolicy,
+}
+
+/// Policy deciding when `WAL::write` and `WAL::batch_write` call fsync.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum SyncPolicy {
+    /// fsync after every write call
+    Always,
+
+    /// fsync once at least N entries have been written since the last sync
+    Entries(usize),
+
+    /// fsync on the next write once the given duration has passed since the last sync,
+    /// entries written before a pause are only synced by `WAL::sync_if_due`
+    Interval(Duration),
+
+    /// only fsync when a segment is full or `WAL::sync` is called
+    #[default]
+    Never,
 }
@@ -1,6 +1,7 @@
 use std::fs::File;
 use std::io::Result;
 use std::os::unix::fs::FileExt;
+use std::path::Path;
 
 pub fn write_at(f: &File, buf: &[u8], offset: u64) -> Result<usize> {
     f.write_at(buf, offset)
@@ -9,3 +10,7 @@ pub fn write_at(f: &File, buf: &[u8], offset: u64) -> Result<usize> {
 pub fn read_at(f: &File, buf: &mut [u8], offset: u64) -> Result<usize> {
     f.read_at(buf, offset)
 }
+
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()
+}
@@ -1,6 +1,7 @@
 use std::fs::File;
 use std::io::Result;
 use std::os::windows::FileExt;
+use std::path::Path;
 
 pub fn write_at(f: &mut File, buf: &[u8], offset: u64) -> Result<usize> {
     f.seek_write(buf, offset)
@@ -9,3 +10,8 @@ pub fn write_at(f: &mut File, buf: &[u8], offset: u64) -> Result<usize> {
 pub fn read_at(f: &File, buf: &mut [u8], offset: u64) -> Result<usize> {
     f.seek_read(buf, offset)
 }
+
+pub fn sync_dir(_dir: &Path) -> Result<()> {
+    // directories can not be opened as files on windows, renames are journaled by ntfs
+    Ok(())
+}
@@ -16,7 +16,7 @@ use std::io::{Error, ErrorKind, Result};
 
 pub fn read_exact_at(f: &File, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
     while !buf.is_empty() {
-        match read_at(f, &mut buf, offset) {
+        match read_at(f, buf, offset) {
             Ok(0) => break,
             Ok(n) => {
                 offset += n as u64;
@@ -7,6 +7,7 @@ extern crate byteorder;
 extern crate crc;
 extern crate fs2;
 extern crate hex;
+extern crate memmap2;
 
 #[cfg(test)]
 extern crate rand;
@@ -19,5 +20,8 @@ mod wal;
 #[cfg(test)]
 mod mock;
 
-pub use config::Config;
-pub use wal::WAL;
+pub use config::{Config, SyncPolicy};
+pub use wal::{
+    CommitToken, Consumer, Entry, EntryReader, EntryTooLarge, EntryWriter, Iter, Location,
+    MappedSegment, Position, RecoveryReport, RevIter, SharedWAL, WAL,
+};
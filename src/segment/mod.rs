@@ -1,25 +1,41 @@
 mod overhead;
 
-use self::overhead::{Overhead, OVERHEAD_SIZE};
+use self::overhead::{Overhead, LEGACY_OVERHEAD_SIZE, OVERHEAD_SIZE};
+pub use self::overhead::{BATCH, BATCH_END};
 use super::fileext;
 use byteorder::{BigEndian, ByteOrder};
-use crc::crc32::{Digest, Hasher32, IEEE};
-use hex::encode;
+use crc::crc32::{checksum_ieee, Digest, Hasher32, IEEE};
+use hex::{decode, encode};
+use memmap2::Mmap;
 use std::ffi::OsStr;
-use std::fs::{remove_file, File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Result as IOResult, Seek, SeekFrom};
+use std::fs::{read_dir, remove_file, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result as IOResult, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-const MAGIC_NUM: [u8; 16] = [
+// magic num of version 1 segments, whose overheads carry no checksum
+const LEGACY_MAGIC_NUM: [u8; 16] = [
     17, 116, 239, 237, 171, 24, 96, 0, 17, 116, 239, 237, 171, 24, 96, 117,
 ];
+const MAGIC_NUM: [u8; 16] = [
+    17, 116, 239, 237, 171, 24, 96, 0, 17, 116, 239, 237, 171, 24, 96, 2,
+];
 const MAGIC_SIZE: usize = 16;
 const ENTRY_LIMIT_SIZE: usize = 8;
+const FIRST_LSN_SIZE: usize = 8;
+const CREATED_SIZE: usize = 8;
 const DEFAULT_ENTRY_LIMIT: usize = 10 << 10;
-const HEAD_SIZE: usize = MAGIC_SIZE + ENTRY_LIMIT_SIZE;
+const LEGACY_HEAD_SIZE: usize = MAGIC_SIZE + ENTRY_LIMIT_SIZE;
+// magic num | entry limit | first lsn | created (millis since epoch) | reserved
+const HEAD_SIZE: usize = 64;
+
+const LEGACY_VERSION: u8 = 1;
+const VERSION: u8 = 2;
 
 pub struct Segment {
     sequence: u64,
+    version: u8,
 
     fname: PathBuf,
     file: File,
@@ -28,6 +44,10 @@ pub struct Segment {
     entry_limit: usize,
     data_written: usize,
 
+    first_lsn: u64,
+    created: SystemTime,
+    max_entry_size: usize,
+
     overhead: Overhead,
     crc32: Digest,
 }
@@ -37,10 +57,47 @@ impl Segment {
         u64_to_hex(sequence)
     }
 
+    /// Returns the sequences of all segment files in the dir, in ascending order.
+    pub fn sequences<P: AsRef<OsStr> + ?Sized>(dir: &P) -> IOResult<Vec<u64>> {
+        let mut sequences = Vec::new();
+        for dir_entry in read_dir(Path::new(dir))? {
+            let dir_entry = dir_entry?;
+            if !dir_entry.file_type()?.is_file() {
+                continue;
+            }
+
+            if let Some(seq) = dir_entry.file_name().to_str().and_then(hex_to_u64) {
+                sequences.push(seq);
+            }
+        }
+
+        sequences.sort_unstable();
+        Ok(sequences)
+    }
+
+    /// Returns true if the head of the segment file is missing, as left by a crash right
+    /// after the file was created. Such a segment can not hold any entry.
+    pub fn torn_head<P: AsRef<OsStr> + ?Sized>(dir: &P, sequence: u64) -> IOResult<bool> {
+        let f = File::open(Path::new(dir).join(Segment::filename(sequence)))?;
+        let size = f.metadata()?.len() as usize;
+        if size < MAGIC_SIZE {
+            return Ok(true);
+        }
+
+        let mut magic = [0; MAGIC_SIZE];
+        fileext::read_exact_at(&f, &mut magic, 0)?;
+
+        Ok(magic == [0; MAGIC_SIZE]
+            || (magic == MAGIC_NUM && size < HEAD_SIZE)
+            || (magic == LEGACY_MAGIC_NUM && size < LEGACY_HEAD_SIZE))
+    }
+
+    /// Opens the segment file, `limit` and `first_lsn` are only used if it is created.
     pub fn open<P: AsRef<OsStr> + ?Sized>(
         dir: &P,
         sequence: u64,
         mut limit: usize,
+        first_lsn: u64,
         create: bool,
     ) -> IOResult<Segment> {
         if limit == 0 {
@@ -56,65 +113,135 @@ impl Segment {
 
         let meta = file.metadata()?;
         if meta.len() == 0 {
-            prepare(&mut file, limit)?;
+            prepare(&mut file, limit, first_lsn)?;
         }
 
-        let (entry_limit, entry_number) = read_info(&mut file)?;
+        let (version, entry_limit, entry_number, first_lsn, created) = read_info(&file, sequence)?;
+
+        // segments written before the creation time was kept are aged from now on
+        let created = match created {
+            0 => SystemTime::now(),
+            millis => UNIX_EPOCH + Duration::from_millis(millis),
+        };
 
         let data_written = file.seek(SeekFrom::End(0))?;
 
         Ok(Segment {
-            sequence: sequence,
-            fname: fname,
-            file: file,
-            entry_limit: entry_limit,
-            entry_number: entry_number,
+            sequence,
+            version,
+            fname,
+            file,
+            entry_limit,
+            entry_number,
             data_written: data_written as usize,
+            first_lsn,
+            created,
+            max_entry_size: 0,
             overhead: Overhead::new(),
             crc32: Digest::new(IEEE),
         })
     }
 
     pub fn write(&mut self, entry: &[u8]) -> IOResult<bool> {
-        if self.entry_number >= self.entry_limit {
+        self.write_flagged(entry, 0)
+    }
+
+    fn write_flagged(&mut self, entry: &[u8], flags: u8) -> IOResult<bool> {
+        if self.space() == 0 {
             return Ok(false);
         }
 
-        let offset = self.data_written as u64;
-
-        fileext::write_all_at(&self.file, entry, offset)?;
-        let written = entry.len();
-
-        self.data_written += written;
+        self.write_pending(entry, 0)?;
 
         self.crc32.reset();
         self.crc32.write(entry);
+        let crc32 = self.crc32.sum32();
+
+        self.publish(entry.len() as u64, crc32, flags)?;
+
+        Ok(true)
+    }
+
+    /// Writes bytes of the next entry at the offset within it, which stays invisible
+    /// until it is published.
+    pub fn write_pending(&mut self, buf: &[u8], offset: u64) -> IOResult<()> {
+        fileext::write_all_at(&self.file, buf, self.data_written as u64 + offset)
+    }
+
+    /// Publishes the next entry of the size, whose bytes are written by `write_pending`.
+    pub fn publish(&mut self, size: u64, crc32: u32, flags: u8) -> IOResult<()> {
+        if self.space() == 0 {
+            return Err(Error::other("segment is full"));
+        }
+
+        let offset = self.data_written as u64;
 
         self.overhead.write_head();
         self.overhead.write_offset(offset);
-        self.overhead.write_size(entry.len() as u64);
-        self.overhead.write_crc32(self.crc32.sum32());
+        self.overhead.write_size(size);
+        self.overhead.write_crc32(crc32);
+        self.overhead.write_flags(flags);
+        self.overhead.write_checksum();
 
-        let overhead_offset = (HEAD_SIZE + self.entry_number * OVERHEAD_SIZE) as u64;
+        let overhead_offset = self.overhead_offset(self.entry_number);
         fileext::write_all_at(&self.file, self.overhead.bytes(), overhead_offset)?;
 
+        self.data_written += size as usize;
         self.entry_number += 1;
 
-        Ok(true)
+        Ok(())
     }
 
-    pub fn batch_write(&mut self, mut entries: &[&[u8]]) -> IOResult<usize> {
-        let size = entries.len();
+    /// Drops the bytes written by `write_pending` which are not published.
+    pub fn discard_pending(&mut self) -> IOResult<()> {
+        self.file.set_len(self.data_written as u64)
+    }
 
-        while !entries.is_empty() {
-            match self.write(entries[0]) {
-                Ok(false) => break,
-                Ok(true) => entries = &entries[1..],
-                Err(e) => return Err(e),
-            }
+    /// Writes the entries with the flags, the last one of them with last_flags instead.
+    ///
+    /// The data of all the entries is laid out contiguously and written at once, followed
+    /// by a single write of their overheads.
+    pub fn batch_write(&mut self, entries: &[&[u8]], flags: u8, last_flags: u8) -> IOResult<usize> {
+        let n = entries.len().min(self.space());
+        if n == 0 {
+            return Ok(0);
         }
 
-        Ok(size - entries.len())
+        let data_size = entries[..n].iter().map(|e| e.len()).sum();
+        let mut data = Vec::with_capacity(data_size);
+        let mut overheads = Vec::with_capacity(n * OVERHEAD_SIZE);
+
+        let mut offset = self.data_written as u64;
+        for (i, entry) in entries[..n].iter().enumerate() {
+            data.extend_from_slice(entry);
+
+            self.crc32.reset();
+            self.crc32.write(entry);
+
+            self.overhead.write_head();
+            self.overhead.write_offset(offset);
+            self.overhead.write_size(entry.len() as u64);
+            self.overhead.write_crc32(self.crc32.sum32());
+            self.overhead.write_flags(if i + 1 == entries.len() {
+                last_flags
+            } else {
+                flags
+            });
+            self.overhead.write_checksum();
+            overheads.extend_from_slice(self.overhead.bytes());
+
+            offset += entry.len() as u64;
+        }
+
+        fileext::write_all_at(&self.file, &data, self.data_written as u64)?;
+
+        let overhead_offset = self.overhead_offset(self.entry_number);
+        fileext::write_all_at(&self.file, &overheads, overhead_offset)?;
+
+        self.data_written += data_size;
+        self.entry_number += n;
+
+        Ok(n)
     }
 
     pub fn read_into(
@@ -136,31 +263,23 @@ impl Segment {
             return Ok(0);
         }
 
-        let mut buf = vec![0; limit * OVERHEAD_SIZE];
-        let offset = (HEAD_SIZE + start * OVERHEAD_SIZE) as u64;
+        let overhead_size = self.overhead_size();
+        let mut buf = vec![0; limit * overhead_size];
         let mut temp = Vec::with_capacity(limit);
 
-        fileext::read_exact_at(&self.file, &mut buf, offset)?;
+        fileext::read_exact_at(&self.file, &mut buf, self.overhead_offset(start))?;
 
         let mut read: usize = 0;
         let mut overhead = Overhead::new();
         let mut digest = Digest::new(IEEE);
         while read < limit {
-            overhead.copy_bytes(&buf[read * OVERHEAD_SIZE..(read + 1) * OVERHEAD_SIZE]);
-            if !overhead.valid() {
-                return Err(Error::new(ErrorKind::InvalidData, "invalid overhead"));
+            overhead.copy_bytes(&buf[read * overhead_size..(read + 1) * overhead_size]);
+            if let Some(reason) = self.check_overhead(&overhead) {
+                return Err(self.corrupted(start + read, reason));
             }
 
             let mut entry = vec![0; overhead.size() as usize];
-            fileext::read_exact_at(&self.file, &mut entry, overhead.offset())?;
-
-            if check {
-                digest.reset();
-                digest.write(&entry);
-                if digest.sum32() != overhead.crc32() {
-                    return Err(Error::new(ErrorKind::InvalidData, "fail to check crc32"));
-                }
-            }
+            self.read_entry(&overhead, &mut entry, &mut digest, check)?;
             temp.push(entry);
 
             read += 1;
@@ -171,15 +290,405 @@ impl Segment {
         Ok(read)
     }
 
+    /// Reads entries like `read_into`, but appends them to one buffer and records the
+    /// range of each entry in it.
+    pub fn read_into_buf(
+        &self,
+        start: usize,
+        mut limit: usize,
+        buf: &mut Vec<u8>,
+        ranges: &mut Vec<Range<usize>>,
+        table: &mut Vec<u8>,
+        check: bool,
+    ) -> IOResult<usize> {
+        if start >= self.entry_number {
+            return Ok(0);
+        }
+
+        limit = limit.min(self.entry_number - start);
+        if limit == 0 {
+            return Ok(0);
+        }
+
+        let overhead_size = self.overhead_size();
+        table.clear();
+        table.resize(limit * overhead_size, 0);
+        fileext::read_exact_at(&self.file, table, self.overhead_offset(start))?;
+
+        let mut overhead = Overhead::new();
+        let mut digest = Digest::new(IEEE);
+        for (i, slot) in table.chunks(overhead_size).enumerate() {
+            overhead.copy_bytes(slot);
+            if let Some(reason) = self.check_overhead(&overhead) {
+                return Err(self.corrupted(start + i, reason));
+            }
+
+            let begin = buf.len();
+            buf.resize(begin + overhead.size() as usize, 0);
+            self.read_entry(&overhead, &mut buf[begin..], &mut digest, check)?;
+            ranges.push(begin..buf.len());
+        }
+
+        Ok(limit)
+    }
+
+    // Reads the entry described by the overhead into dst, which has the size of it.
+    fn read_entry(
+        &self,
+        overhead: &Overhead,
+        dst: &mut [u8],
+        digest: &mut Digest,
+        check: bool,
+    ) -> IOResult<()> {
+        fileext::read_exact_at(&self.file, dst, overhead.offset())?;
+
+        if check {
+            digest.reset();
+            digest.write(dst);
+            if digest.sum32() != overhead.crc32() {
+                return Err(Error::new(ErrorKind::InvalidData, "fail to check crc32"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns how many of the entries after start fit in the byte budget, judged by the
+    /// sizes in the overheads, along with the bytes they take.
+    pub fn fit(&self, start: usize, mut limit: usize, budget: usize) -> IOResult<(usize, usize)> {
+        if start >= self.entry_number {
+            return Ok((0, 0));
+        }
+
+        limit = limit.min(self.entry_number - start);
+        if limit == 0 {
+            return Ok((0, 0));
+        }
+
+        let overhead_size = self.overhead_size();
+        let mut buf = vec![0; limit * overhead_size];
+        fileext::read_exact_at(&self.file, &mut buf, self.overhead_offset(start))?;
+
+        let mut fitted: usize = 0;
+        let mut bytes: usize = 0;
+        let mut overhead = Overhead::new();
+        for slot in buf.chunks(overhead_size) {
+            overhead.copy_bytes(slot);
+            if let Some(reason) = self.check_overhead(&overhead) {
+                return Err(self.corrupted(start + fitted, reason));
+            }
+
+            let size = overhead.size() as usize;
+            if size > budget - bytes {
+                break;
+            }
+
+            bytes += size;
+            fitted += 1;
+        }
+
+        Ok((fitted, bytes))
+    }
+
+    /// Returns the offset, size and crc32 of the entry at the index.
+    pub fn entry_span(&self, index: usize) -> IOResult<(u64, u64, u32)> {
+        if index >= self.entry_number {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "entry index out of range",
+            ));
+        }
+
+        let size = self.overhead_size();
+        let mut buf = [0; OVERHEAD_SIZE];
+        fileext::read_exact_at(&self.file, &mut buf[..size], self.overhead_offset(index))?;
+
+        let mut overhead = Overhead::new();
+        overhead.copy_bytes(&buf[..size]);
+        if let Some(reason) = self.check_overhead(&overhead) {
+            return Err(self.corrupted(index, reason));
+        }
+
+        Ok((overhead.offset(), overhead.size(), overhead.crc32()))
+    }
+
+    // Returns the offset of the entry at the index, regardless of the entry size limit
+    // which only applies to reading entries.
+    fn entry_offset(&self, index: usize) -> IOResult<u64> {
+        let size = self.overhead_size();
+        let mut buf = [0; OVERHEAD_SIZE];
+        fileext::read_exact_at(&self.file, &mut buf[..size], self.overhead_offset(index))?;
+
+        let mut overhead = Overhead::new();
+        overhead.copy_bytes(&buf[..size]);
+        if let Some(reason) = self.check_integrity(&overhead) {
+            return Err(self.corrupted(index, reason));
+        }
+
+        Ok(overhead.offset())
+    }
+
+    /// Reads the segment file at the offset, returns the number of bytes read.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> IOResult<usize> {
+        fileext::read_at(&self.file, buf, offset)
+    }
+
+    /// Maps the segment file into memory, for segments which are not written any more.
+    pub fn map(&self) -> IOResult<Mmap> {
+        // sealed segments are only modified through `&mut Segment`, which the callers
+        // can not get while the map is borrowed
+        unsafe { Mmap::map(&self.file) }
+    }
+
+    /// Returns the entry at the index out of the mapped segment file, without any copy.
+    pub fn mapped_entry<'m>(&self, map: &'m [u8], index: usize, check: bool) -> IOResult<&'m [u8]> {
+        if index >= self.entry_number {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "entry index out of range",
+            ));
+        }
+
+        let start = self.overhead_offset(index) as usize;
+        let slot = map.get(start..start + self.overhead_size()).unwrap_or(&[]);
+        let mut overhead = Overhead::new();
+        if !overhead.copy_bytes(slot) {
+            return Err(self.corrupted(index, "missing overhead"));
+        }
+
+        if let Some(reason) = self.check_overhead(&overhead) {
+            return Err(self.corrupted(index, reason));
+        }
+
+        let offset = overhead.offset() as usize;
+        let entry = map
+            .get(offset..offset + overhead.size() as usize)
+            .ok_or_else(|| self.corrupted(index, "entry out of bounds"))?;
+
+        if check && checksum_ieee(entry) != overhead.crc32() {
+            return Err(Error::new(ErrorKind::InvalidData, "fail to check crc32"));
+        }
+
+        Ok(entry)
+    }
+
+    /// Validates every entry and truncates the segment after the last good one.
+    ///
+    /// Returns the number of discarded entries and truncated bytes.
+    pub fn recover(&mut self) -> IOResult<(usize, u64)> {
+        let file_size = self.data_written as u64;
+        let overhead_size = self.overhead_size();
+        let slots = self
+            .entry_limit
+            .min((self.data_written.saturating_sub(head_size(self.version))) / overhead_size);
+        let mut buf = vec![0; slots * overhead_size];
+        fileext::read_exact_at(&self.file, &mut buf, self.overhead_offset(0))?;
+
+        let mut good: usize = 0;
+        let mut expected = self.data_offset();
+        let mut overhead = Overhead::new();
+        let mut digest = Digest::new(IEEE);
+        while good < self.entry_number {
+            overhead.copy_bytes(&buf[good * overhead_size..(good + 1) * overhead_size]);
+            if self.check_overhead(&overhead).is_some()
+                || overhead.offset() != expected
+                || overhead.size() > file_size.saturating_sub(expected)
+            {
+                break;
+            }
+
+            let mut entry = vec![0; overhead.size() as usize];
+            fileext::read_exact_at(&self.file, &mut entry, overhead.offset())?;
+
+            digest.reset();
+            digest.write(&entry);
+            if digest.sum32() != overhead.crc32() {
+                break;
+            }
+
+            expected += overhead.size();
+            good += 1;
+        }
+
+        // records left after the first missing one by an interrupted truncation
+        let end = buf
+            .chunks(overhead_size)
+            .rposition(|slot| overhead.copy_bytes(slot) && overhead.valid())
+            .map_or(0, |i| i + 1)
+            .max(self.entry_number);
+
+        let discarded = self.entry_number - good;
+        let truncated = file_size.saturating_sub(expected);
+        if discarded > 0 || truncated > 0 || end > self.entry_number {
+            self.truncate_to(good, end, expected)?;
+        }
+
+        Ok((discarded, truncated))
+    }
+
+    /// Returns the number of entries at the tail written by an atomic batch which does
+    /// not end in this segment.
+    pub fn unended_batch(&self) -> IOResult<usize> {
+        let overhead_size = self.overhead_size();
+        let mut buf = vec![0; self.entry_number * overhead_size];
+        fileext::read_exact_at(&self.file, &mut buf, self.overhead_offset(0))?;
+
+        let mut overhead = Overhead::new();
+        Ok(buf
+            .chunks(overhead_size)
+            .rev()
+            .take_while(|slot| {
+                overhead.copy_bytes(slot);
+                overhead.flags() & (BATCH | BATCH_END) == BATCH
+            })
+            .count())
+    }
+
+    /// Discards the entries after the first len ones along with any unpublished bytes,
+    /// returns the number of bytes truncated.
+    pub fn truncate(&mut self, len: usize) -> IOResult<u64> {
+        let len = len.min(self.entry_number);
+        let data_end = if len < self.entry_number {
+            self.entry_offset(len)?
+        } else {
+            self.data_written as u64
+        };
+
+        let truncated = self.data_written as u64 - data_end;
+        let end = self.entry_number;
+        self.truncate_to(len, end, data_end)?;
+
+        Ok(truncated)
+    }
+
+    // Clears the overheads in [len, end) and cuts the data at data_end.
+    fn truncate_to(&mut self, len: usize, end: usize, data_end: u64) -> IOResult<()> {
+        if len < end {
+            // the first record is cleared last, so a crash in the middle leaves a prefix
+            // of the entries rather than a hole followed by stale records
+            let size = self.overhead_size();
+            let empty = vec![0; (end - len) * size];
+            fileext::write_all_at(&self.file, &empty[size..], self.overhead_offset(len + 1))?;
+            self.file.sync_data()?;
+            fileext::write_all_at(&self.file, &empty[..size], self.overhead_offset(len))?;
+        }
+
+        self.file.set_len(data_end)?;
+        self.file.sync_all()?;
+
+        self.entry_number = len;
+        self.data_written = data_end as usize;
+
+        Ok(())
+    }
+
+    // Returns the reason if the overhead record is damaged, or its entry is too large to read.
+    fn check_overhead(&self, overhead: &Overhead) -> Option<&'static str> {
+        if let Some(reason) = self.check_integrity(overhead) {
+            return Some(reason);
+        }
+
+        if self.max_entry_size > 0 && overhead.size() > self.max_entry_size as u64 {
+            return Some("entry too large");
+        }
+
+        None
+    }
+
+    // Returns the reason if the overhead record is damaged.
+    fn check_integrity(&self, overhead: &Overhead) -> Option<&'static str> {
+        if !overhead.valid() {
+            return Some("missing overhead");
+        }
+
+        if self.version != LEGACY_VERSION && !overhead.checksum_valid() {
+            return Some("overhead checksum mismatch");
+        }
+
+        if overhead.offset() < self.data_offset()
+            || overhead.size() > (self.data_written as u64).saturating_sub(overhead.offset())
+        {
+            return Some("entry out of bounds");
+        }
+
+        None
+    }
+
+    fn corrupted(&self, index: usize, reason: &str) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{}: entry {} of segment {}",
+                reason,
+                index,
+                Segment::filename(self.sequence)
+            ),
+        )
+    }
+
+    fn overhead_size(&self) -> usize {
+        overhead_size(self.version)
+    }
+
+    fn overhead_offset(&self, index: usize) -> u64 {
+        (head_size(self.version) + index * self.overhead_size()) as u64
+    }
+
+    fn data_offset(&self) -> u64 {
+        self.overhead_offset(self.entry_limit)
+    }
+
     pub fn sequence(&self) -> u64 {
         self.sequence
     }
 
+    /// Returns the lsn of the first entry in this segment.
+    pub fn first_lsn(&self) -> u64 {
+        self.first_lsn
+    }
+
+    /// Returns the lsn the next appended entry will get.
+    pub fn next_lsn(&self) -> u64 {
+        self.first_lsn + self.entry_number as u64
+    }
+
+    /// Returns true if the segment is in the version 1 format, which is read only.
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_VERSION
+    }
+
+    /// Legacy segments have no lsn in their head, it is derived from the next segment.
+    pub fn derive_first_lsn(&mut self, first_lsn: u64) {
+        if self.version == LEGACY_VERSION {
+            self.first_lsn = first_lsn;
+        }
+    }
+
+    /// Limits the size of the entries to read, 0 for no limit.
+    pub fn set_max_entry_size(&mut self, size: usize) {
+        self.max_entry_size = size;
+    }
+
+    /// Returns how long ago the segment was created.
+    pub fn age(&self) -> Duration {
+        self.created.elapsed().unwrap_or_default()
+    }
+
     pub fn len(&self) -> usize {
         self.entry_number
     }
 
+    /// Returns the size of the entries in the segment.
+    pub fn data_size(&self) -> u64 {
+        (self.data_written as u64).saturating_sub(self.data_offset())
+    }
+
+    /// Returns how many entries can still be appended, legacy segments are read only.
     pub fn space(&self) -> usize {
+        if self.is_legacy() {
+            return 0;
+        }
+
         self.entry_limit - self.entry_number
     }
 
@@ -198,42 +707,115 @@ fn u64_to_hex(n: u64) -> String {
     encode(buf)
 }
 
-fn prepare(f: &mut File, entry_limit: usize) -> IOResult<()> {
+fn hex_to_u64(s: &str) -> Option<u64> {
+    if s.len() != 16 {
+        return None;
+    }
+
+    decode(s).ok().map(|buf| BigEndian::read_u64(&buf))
+}
+
+fn prepare(f: &mut File, entry_limit: usize, first_lsn: u64) -> IOResult<()> {
     fileext::allocate(f, HEAD_SIZE + entry_limit * OVERHEAD_SIZE)?;
-    fileext::write_all_at(f, &MAGIC_NUM[..], 0)?;
 
-    let mut size_buf: [u8; 8] = [0; 8];
-    BigEndian::write_u64(&mut size_buf, entry_limit as u64);
-    fileext::write_all_at(f, &size_buf, MAGIC_SIZE as u64)
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    let mut buf = [0; MAGIC_SIZE + ENTRY_LIMIT_SIZE + FIRST_LSN_SIZE + CREATED_SIZE];
+    let (lsn_at, created_at) = (LEGACY_HEAD_SIZE, LEGACY_HEAD_SIZE + FIRST_LSN_SIZE);
+    buf[..MAGIC_SIZE].copy_from_slice(&MAGIC_NUM);
+    BigEndian::write_u64(&mut buf[MAGIC_SIZE..lsn_at], entry_limit as u64);
+    BigEndian::write_u64(&mut buf[lsn_at..created_at], first_lsn);
+    BigEndian::write_u64(&mut buf[created_at..], created);
+    fileext::write_all_at(f, &buf, 0)?;
+
+    // the head is on disk before the file shows up in the dir
+    f.sync_all()
 }
 
-fn read_info(f: &mut File) -> IOResult<(usize, usize)> {
-    f.seek(SeekFrom::Start(0))?;
+fn head_size(version: u8) -> usize {
+    if version == LEGACY_VERSION {
+        LEGACY_HEAD_SIZE
+    } else {
+        HEAD_SIZE
+    }
+}
 
-    let mut buf = [0; MAGIC_SIZE];
-    f.read_exact(&mut buf[..])?;
-    if buf != MAGIC_NUM {
-        return Err(Error::new(ErrorKind::Other, "invalid magic num"));
+fn overhead_size(version: u8) -> usize {
+    if version == LEGACY_VERSION {
+        LEGACY_OVERHEAD_SIZE
+    } else {
+        OVERHEAD_SIZE
     }
+}
 
-    f.read_exact(&mut buf[..ENTRY_LIMIT_SIZE])?;
-    let entry_limit = BigEndian::read_u64(&buf[..ENTRY_LIMIT_SIZE]) as usize;
+// Returns format version, entry limit, entry number, first lsn and creation time of the
+// segment file.
+fn read_info(f: &File, sequence: u64) -> IOResult<(u8, usize, usize, u64, u64)> {
+    let mut buf = [0; LEGACY_HEAD_SIZE + FIRST_LSN_SIZE + CREATED_SIZE];
+    fileext::read_exact_at(f, &mut buf[..LEGACY_HEAD_SIZE], 0)?;
+
+    let version = if buf[..MAGIC_SIZE] == MAGIC_NUM {
+        VERSION
+    } else if buf[..MAGIC_SIZE] == LEGACY_MAGIC_NUM {
+        LEGACY_VERSION
+    } else {
+        return Err(Error::other("invalid magic num"));
+    };
+
+    let entry_limit = BigEndian::read_u64(&buf[MAGIC_SIZE..LEGACY_HEAD_SIZE]) as usize;
+
+    let (first_lsn, created) = if version == LEGACY_VERSION {
+        (0, 0)
+    } else {
+        fileext::read_exact_at(f, &mut buf[LEGACY_HEAD_SIZE..], LEGACY_HEAD_SIZE as u64)?;
+        let created_at = LEGACY_HEAD_SIZE + FIRST_LSN_SIZE;
+        (
+            BigEndian::read_u64(&buf[LEGACY_HEAD_SIZE..created_at]),
+            BigEndian::read_u64(&buf[created_at..]),
+        )
+    };
+
+    let head_size = head_size(version);
+    let overhead_size = overhead_size(version);
+    let file_size = f.metadata()?.len() as usize;
+    let table_size = (entry_limit * overhead_size).min(file_size.saturating_sub(head_size));
+
+    let mut table = vec![0; table_size];
+    fileext::read_exact_at(f, &mut table, head_size as u64)?;
 
     let mut num = 0_usize;
     let mut oh = Overhead::new();
-    while num < entry_limit {
-        oh.reset();
-        let _ = oh.read_from(f);
+    for slot in table.chunks(overhead_size) {
+        if slot.len() < overhead_size || !oh.copy_bytes(slot) || !oh.valid() {
+            break;
+        }
+
+        if version != LEGACY_VERSION && !oh.checksum_valid() {
+            // a torn record at the tail is left to recovery, anything else is corruption
+            let mut next = Overhead::new();
+            let rest = &table[(num + 1) * overhead_size..];
+            if next.copy_bytes(&rest[..overhead_size.min(rest.len())]) && next.valid() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "overhead checksum mismatch: entry {} of segment {}",
+                        num,
+                        Segment::filename(sequence)
+                    ),
+                ));
+            }
 
-        if !oh.valid() {
             break;
         }
 
         num += 1;
     }
 
-    Ok((entry_limit, num))
+    Ok((version, entry_limit, num, first_lsn, created))
 }
 
 #[cfg(test)]
-mod tests;
+pub mod tests;
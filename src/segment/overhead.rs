@@ -1,7 +1,22 @@
 use byteorder::{BigEndian, ByteOrder};
-use std::io;
+use crc::crc32::checksum_ieee;
 
-pub const OVERHEAD_SIZE: usize = 22;
+/// size of an overhead record in the current format.
+///
+/// head(2) | offset(8) | size(8) | crc32(4) | flags(1) | reserved(5) | checksum(4)
+pub const OVERHEAD_SIZE: usize = 32;
+
+/// flag of the entries written by an atomic batch.
+pub const BATCH: u8 = 0x01;
+
+/// flag of the last entry of an atomic batch.
+pub const BATCH_END: u8 = 0x02;
+
+/// size of an overhead record in version 1 segments, which has no checksum.
+pub const LEGACY_OVERHEAD_SIZE: usize = 22;
+
+const FLAGS_OFFSET: usize = 22;
+const CHECKSUM_OFFSET: usize = OVERHEAD_SIZE - 4;
 const EMPTY: [u8; OVERHEAD_SIZE] = [0; OVERHEAD_SIZE];
 
 #[derive(Debug)]
@@ -32,6 +47,16 @@ impl Overhead {
         BigEndian::write_u32(&mut self.0[18..22], crc32);
     }
 
+    pub fn write_flags(&mut self, flags: u8) {
+        self.0[FLAGS_OFFSET] = flags;
+    }
+
+    /// Seals the record with a checksum of all the former fields.
+    pub fn write_checksum(&mut self) {
+        let checksum = checksum_ieee(&self.0[..CHECKSUM_OFFSET]);
+        BigEndian::write_u32(&mut self.0[CHECKSUM_OFFSET..], checksum);
+    }
+
     pub fn bytes(&self) -> &[u8] {
         &self.0[..]
     }
@@ -48,24 +73,27 @@ impl Overhead {
         BigEndian::read_u32(&self.0[18..22])
     }
 
+    pub fn flags(&self) -> u8 {
+        self.0[FLAGS_OFFSET]
+    }
+
     pub fn valid(&self) -> bool {
         self.0[0] == 0x01 && self.0[1] == 0xff
     }
 
+    pub fn checksum_valid(&self) -> bool {
+        BigEndian::read_u32(&self.0[CHECKSUM_OFFSET..]) == checksum_ieee(&self.0[..CHECKSUM_OFFSET])
+    }
+
+    /// Loads a record of either format, the missing tail of a legacy record is zeroed.
     pub fn copy_bytes(&mut self, src: &[u8]) -> bool {
-        if src.len() < 22 {
+        if src.len() < LEGACY_OVERHEAD_SIZE {
             return false;
         }
 
-        self.0[..].copy_from_slice(&src[..22]);
-        return true;
-    }
-
-    pub fn reset(&mut self) {
-        self.copy_bytes(&EMPTY[..]);
-    }
-
-    pub fn read_from<T: io::Read>(&mut self, r: &mut T) -> io::Result<()> {
-        r.read_exact(&mut self.0[..])
+        let size = src.len().min(OVERHEAD_SIZE);
+        self.0[..size].copy_from_slice(&src[..size]);
+        self.0[size..].copy_from_slice(&EMPTY[size..]);
+        true
     }
 }
@@ -1,12 +1,19 @@
+use super::overhead::LEGACY_OVERHEAD_SIZE;
+use super::{LEGACY_HEAD_SIZE, LEGACY_MAGIC_NUM, MAGIC_SIZE};
+use byteorder::{BigEndian, ByteOrder};
+use crc::crc32::checksum_ieee;
+use fileext;
 use mock::{random_bytes, Home};
 use segment::Segment;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
 use std::path::Path;
 
 #[test]
 fn test_create_destory() {
-    let testhome = Home::new("testdir");
+    let testhome = Home::new("testdir_segment_create_destory");
 
-    let mut seq = Segment::open(&testhome.dir(), 1, 0, true).unwrap();
+    let mut seq = Segment::open(&testhome.dir(), 1, 0, 0, true).unwrap();
     let fname = Path::new(&testhome.dir()).join(Segment::filename(1));
 
     assert!(fname.exists() && fname.is_file());
@@ -18,9 +25,9 @@ fn test_create_destory() {
 
 #[test]
 fn test_read_write() {
-    let testhome = Home::new("testdir");
+    let testhome = Home::new("testdir_segment_read_write");
 
-    let mut seq = Segment::open(&testhome.dir(), 1, 0, true).unwrap();
+    let mut seq = Segment::open(&testhome.dir(), 1, 0, 0, true).unwrap();
 
     let buf_vec = random_bytes(1024);
     let buf = buf_vec.as_slice();
@@ -40,8 +47,8 @@ fn test_read_write() {
     assert_eq!(data.len(), buf.len());
 
     for (i, v) in data.iter().enumerate() {
-        assert_eq!((i + 1) as usize, v.len());
-        assert_eq!(&buf[..(i + 1) as usize].to_vec(), v);
+        assert_eq!(i + 1, v.len());
+        assert_eq!(&buf[..i + 1].to_vec(), v);
     }
 
     data.clear();
@@ -51,16 +58,16 @@ fn test_read_write() {
     assert_eq!(data.len(), 100);
 
     for (i, v) in data.iter().enumerate() {
-        assert_eq!((i + 255 + 1) as usize, v.len());
-        assert_eq!(&buf[..(i + 255 + 1) as usize].to_vec(), v);
+        assert_eq!(i + 255 + 1, v.len());
+        assert_eq!(&buf[..i + 255 + 1].to_vec(), v);
     }
 }
 
 #[test]
 fn test_write_overlimit() {
-    let testhome = Home::new("testdir");
+    let testhome = Home::new("testdir_segment_write_overlimit");
 
-    let mut seq = Segment::open(&testhome.dir(), 1, 128, true).unwrap();
+    let mut seq = Segment::open(&testhome.dir(), 1, 128, 0, true).unwrap();
 
     let buf_vec = random_bytes(128);
     let buf = buf_vec.as_slice();
@@ -73,8 +80,195 @@ fn test_write_overlimit() {
 
     assert_eq!(seq.len(), buf.len());
 
-    match seq.write(&buf) {
+    match seq.write(buf) {
         Ok(false) => {}
         _ => panic!("expecting error `entry limit exceeded`"),
     }
 }
+
+#[test]
+fn test_batch_write() {
+    let testhome = Home::new("testdir_segment_batch_write");
+
+    let mut seg = Segment::open(&testhome.dir(), 1, 100, 0, true).unwrap();
+    let buf = random_bytes(128);
+    let batch: Vec<&[u8]> = (0..128).map(|i| &buf[..i + 1]).collect();
+
+    seg.write(&buf[..3]).unwrap();
+    assert_eq!(seg.batch_write(&batch[..60], 0, 0).unwrap(), 60);
+    assert_eq!(seg.batch_write(&batch[60..], 0, 0).unwrap(), 39);
+    assert_eq!(seg.batch_write(&batch[99..], 0, 0).unwrap(), 0);
+    assert_eq!(seg.len(), 100);
+
+    let mut data: Vec<Vec<u8>> = Vec::with_capacity(100);
+    seg.read_into(0, 100, &mut data, true).unwrap();
+    assert_eq!(&data[0][..], &buf[..3]);
+    for (i, v) in data[1..].iter().enumerate() {
+        assert_eq!(&v[..], batch[i]);
+    }
+
+    assert_eq!(seg.recover().unwrap(), (0, 0));
+    drop(seg);
+
+    let seg = Segment::open(&testhome.dir(), 1, 0, 0, false).unwrap();
+    assert_eq!(seg.len(), 100);
+    assert_eq!(seg.data_size(), 3 + (1..100).sum::<usize>() as u64);
+}
+
+#[test]
+fn test_recover() {
+    let testhome = Home::new("testdir_segment_recover");
+    let fname = Path::new(&testhome.dir()).join(Segment::filename(1));
+    let buf = random_bytes(64);
+
+    {
+        let mut seg = Segment::open(&testhome.dir(), 1, 16, 0, true).unwrap();
+        for i in 0..10 {
+            seg.write(&buf[..i + 1]).unwrap();
+        }
+        assert_eq!(seg.recover().unwrap(), (0, 0));
+    }
+
+    {
+        let title = "data written without overhead";
+        let mut f = OpenOptions::new().append(true).open(&fname).unwrap();
+        f.write_all(&buf).unwrap();
+
+        let mut seg = Segment::open(&testhome.dir(), 1, 16, 0, false).unwrap();
+        assert_eq!(seg.len(), 10, "{}", title);
+        assert_eq!(seg.recover().unwrap(), (0, 64), "{}", title);
+        assert_eq!(seg.len(), 10, "{}", title);
+    }
+
+    {
+        let title = "corrupted data of the last entry";
+        let len = fs::metadata(&fname).unwrap().len();
+        let f = OpenOptions::new().write(true).open(&fname).unwrap();
+        f.set_len(len - 1).unwrap();
+
+        let mut seg = Segment::open(&testhome.dir(), 1, 16, 0, false).unwrap();
+        assert_eq!(seg.recover().unwrap(), (1, 9), "{}", title);
+        assert_eq!(seg.len(), 9, "{}", title);
+
+        let mut data = Vec::new();
+        seg.read_into(0, 16, &mut data, true).unwrap();
+        assert_eq!(data.len(), 9, "{}", title);
+        for (i, v) in data.iter().enumerate() {
+            assert_eq!(&buf[..i + 1], &v[..], "{}", title);
+        }
+
+        seg.write(&buf[..10]).unwrap();
+    }
+
+    {
+        let title = "reopen after recovery";
+        let mut seg = Segment::open(&testhome.dir(), 1, 16, 0, false).unwrap();
+        assert_eq!(seg.len(), 10, "{}", title);
+        assert_eq!(seg.recover().unwrap(), (0, 0), "{}", title);
+    }
+}
+
+#[test]
+fn test_corrupted_overhead() {
+    let testhome = Home::new("testdir_segment_corrupted_overhead");
+    let fname = Path::new(&testhome.dir()).join(Segment::filename(1));
+    let buf = random_bytes(64);
+
+    let seg = {
+        let mut seg = Segment::open(&testhome.dir(), 1, 16, 0, true).unwrap();
+        for i in 0..10 {
+            seg.write(&buf[..i + 1]).unwrap();
+        }
+        seg
+    };
+
+    // flip a bit in the size field of entry 3
+    let size_offset = seg.overhead_offset(3) + 10;
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&fname)
+        .unwrap();
+    let mut b = [0; 1];
+    fileext::read_exact_at(&f, &mut b, size_offset + 7).unwrap();
+    b[0] ^= 0x40;
+    fileext::write_all_at(&f, &b, size_offset + 7).unwrap();
+
+    {
+        let title = "read corrupted entry";
+        let mut data = Vec::new();
+        let err = seg.read_into(0, 16, &mut data, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", title);
+        assert!(
+            err.to_string()
+                .starts_with("overhead checksum mismatch: entry 3 of segment"),
+            "{} {}",
+            title,
+            err
+        );
+        assert!(data.is_empty(), "{}", title);
+    }
+
+    {
+        let title = "open with corrupted entry";
+        let err = Segment::open(&testhome.dir(), 1, 16, 0, false)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", title);
+    }
+
+    {
+        let title = "torn overhead at the tail";
+        let mut seg = Segment::open(&testhome.dir(), 2, 16, 0, true).unwrap();
+        for i in 0..5 {
+            seg.write(&buf[..i + 1]).unwrap();
+        }
+
+        let offset = seg.overhead_offset(4) + 4;
+        fileext::write_all_at(&seg.file, &[0xff; 4], offset).unwrap();
+
+        let mut seg = Segment::open(&testhome.dir(), 2, 16, 0, false).unwrap();
+        assert_eq!(seg.len(), 4, "{}", title);
+        assert_eq!(seg.recover().unwrap(), (0, 5), "{}", title);
+    }
+}
+
+/// Writes a version 1 segment file holding the entries.
+pub fn write_legacy(dir: &Path, sequence: u64, limit: usize, entries: &[&[u8]]) {
+    let data_offset = LEGACY_HEAD_SIZE + limit * LEGACY_OVERHEAD_SIZE;
+    let mut contents = vec![0; data_offset];
+    contents[..MAGIC_SIZE].copy_from_slice(&LEGACY_MAGIC_NUM);
+    BigEndian::write_u64(&mut contents[MAGIC_SIZE..], limit as u64);
+
+    for (i, entry) in entries.iter().enumerate() {
+        let start = LEGACY_HEAD_SIZE + i * LEGACY_OVERHEAD_SIZE;
+        let offset = contents.len();
+        let oh = &mut contents[start..start + LEGACY_OVERHEAD_SIZE];
+        oh[0] = 0x01;
+        oh[1] = 0xff;
+        BigEndian::write_u64(&mut oh[2..10], offset as u64);
+        BigEndian::write_u64(&mut oh[10..18], entry.len() as u64);
+        BigEndian::write_u32(&mut oh[18..22], checksum_ieee(entry));
+        contents.extend_from_slice(entry);
+    }
+
+    fs::write(dir.join(Segment::filename(sequence)), &contents).unwrap();
+}
+
+#[test]
+fn test_legacy_segment() {
+    let testhome = Home::new("testdir_segment_legacy");
+    let entry = random_bytes(10);
+    write_legacy(&testhome.dir(), 1, 4, &[&entry]);
+
+    let mut seg = Segment::open(&testhome.dir(), 1, 16, 0, false).unwrap();
+    assert_eq!(seg.len(), 1);
+    assert_eq!(seg.space(), 0);
+    assert_eq!(seg.recover().unwrap(), (0, 0));
+
+    let mut data = Vec::new();
+    assert_eq!(seg.read_into(0, 16, &mut data, true).unwrap(), 1);
+    assert_eq!(data[0], entry);
+
+    assert!(!seg.write(&entry).unwrap());
+}
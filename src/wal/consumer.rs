@@ -0,0 +1,90 @@
+use super::{CommitToken, Location, Position, WAL};
+use std::io::Result;
+
+/// Consumer reads the wal with its own named cursor.
+///
+/// It is returned by `WAL::open_consumer`, and works the same as the default cursor
+/// used by `WAL::read` and friends.
+pub struct Consumer<'a> {
+    wal: &'a mut WAL,
+    name: String,
+}
+
+impl<'a> Consumer<'a> {
+    pub(crate) fn new(wal: &'a mut WAL, name: String) -> Consumer<'a> {
+        Consumer { wal, name }
+    }
+
+    /// Returns the name of the consumer.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the position of the next entry to read.
+    pub fn position(&self) -> &Position {
+        &self.wal.consumers[&self.name].position
+    }
+
+    /// Read N entries from wal.
+    pub fn read(&mut self, n: usize) -> Result<Vec<Vec<u8>>> {
+        let (result, token) = self.read_pending(n)?;
+        self.commit(token)?;
+
+        Ok(result)
+    }
+
+    /// Returns the next N entries without consuming them, together with a token which
+    /// consumes them once passed to `commit`.
+    pub fn read_pending(&self, n: usize) -> Result<(Vec<Vec<u8>>, CommitToken)> {
+        let mut result: Vec<Vec<u8>> = Vec::with_capacity(n);
+
+        let position = self.wal.collect(self.position(), n, &mut result)?;
+
+        Ok((result, self.wal.token(position)))
+    }
+
+    /// Consumes the entries returned along with the token.
+    ///
+    /// Committing a token which is behind the cursor is a no-op, while a token taken
+    /// before `WAL::truncate_after` is rejected.
+    pub fn commit(&mut self, token: CommitToken) -> Result<()> {
+        self.wal.check_token(&token)?;
+        if &token.position <= self.position() {
+            return Ok(());
+        }
+
+        let position = self.wal.resolve(token.position.into())?;
+        self.wal.move_cursor(Some(&self.name), position)
+    }
+
+    /// Returns the next N entries like `read`, but leaves the cursor untouched.
+    pub fn peek(&self, n: usize) -> Result<Vec<Vec<u8>>> {
+        let mut result: Vec<Vec<u8>> = Vec::with_capacity(n);
+
+        self.wal.collect(self.position(), n, &mut result)?;
+
+        Ok(result)
+    }
+
+    /// Moves the cursor to any entry still on disk.
+    pub fn seek<L: Into<Location>>(&mut self, location: L) -> Result<()> {
+        let position = self.wal.resolve(location.into())?;
+        self.wal.move_cursor(Some(&self.name), position)
+    }
+
+    /// Moves the cursor back to the oldest entry on disk.
+    pub fn rewind(&mut self) -> Result<()> {
+        let position = self.wal.oldest();
+        self.wal.move_cursor(Some(&self.name), position)
+    }
+
+    /// Returns the number of entries left to read.
+    pub fn len(&self) -> usize {
+        self.wal.remaining(self.position())
+    }
+
+    /// Returns true if there is no entry left to read.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
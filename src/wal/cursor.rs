@@ -1,78 +1,205 @@
 use byteorder::{BigEndian, ByteOrder};
+use crc::crc32::checksum_ieee;
 use fileext;
-use std::fs::{write, File, OpenOptions};
-use std::io::{Error, ErrorKind, Result};
-use std::path::PathBuf;
+use std::fs::{read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
 
 const MAGIC_NUM: [u8; 16] = [
     17, 117, 239, 237, 171, 24, 96, 0, 116, 117, 239, 237, 171, 24, 96, 117,
 ];
 
 const CURSOR_FILE_NAME: &str = "cursor";
+// named cursors are stored in `cursor.<name>`
+const CURSOR_FILE_PREFIX: &str = "cursor.";
+const TEMP_FILE_PREFIX: &str = "tmp.";
+// left by a truncation until it is done, with the position of the first removed entry
+const TRUNCATION_FILE_NAME: &str = "truncation";
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+// magic num, sequence, read
+const LEGACY_RECORD_SIZE: usize = 32;
+// magic num, sequence, read, crc32 of the former fields
+const RECORD_SIZE: usize = LEGACY_RECORD_SIZE + 4;
+
+/// Position of an entry in the wal.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Position {
+    /// sequence of the segment
     pub sequence: u64,
+
+    /// index of the entry in the segment
     pub read: u64,
 }
 
 pub struct Cursor {
+    dir: PathBuf,
     fname: PathBuf,
+    temp: PathBuf,
     pub position: Position,
+
+    /// the cursor file exists but could not be decoded
+    pub corrupted: bool,
+
+    /// the cursor file exists, i.e. the cursor has been moved once
+    pub saved: bool,
 }
 
 impl Cursor {
-    pub fn open(dir: &PathBuf) -> Result<Cursor> {
-        let fname = dir.join(CURSOR_FILE_NAME);
-        let file = match OpenOptions::new().read(true).open(&fname) {
-            Ok(f) => f,
-            Err(ref e) if e.kind() == ErrorKind::NotFound => {
-                return Ok(Cursor {
-                    fname: fname,
-                    position: Position {
-                        sequence: 0,
-                        read: 0,
-                    },
-                })
+    /// Opens the default cursor, or the named one of a consumer.
+    pub fn open(dir: &Path, name: Option<&str>) -> Result<Cursor> {
+        let fname = match name {
+            Some(name) => {
+                if !valid_name(name) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "consumer name should only contain ascii letters, digits, '-' or '_'",
+                    ));
+                }
+
+                format!("{}{}", CURSOR_FILE_PREFIX, name)
             }
-            Err(e) => return Err(e),
+            None => CURSOR_FILE_NAME.to_owned(),
         };
 
-        let (sequence, read) = read_position(&file)?;
+        let mut cursor = Cursor::new(dir, &fname);
+        cursor.load()?;
+        Ok(cursor)
+    }
+
+    /// Opens the marker left by an unfinished truncation, if any.
+    pub fn open_truncation(dir: &Path) -> Result<Option<Cursor>> {
+        let mut marker = Cursor::new(dir, TRUNCATION_FILE_NAME);
+        if !marker.load()? {
+            return Ok(None);
+        }
 
-        Ok(Cursor {
-            fname: fname,
+        if marker.corrupted {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "truncation marker is damaged",
+            ));
+        }
+
+        Ok(Some(marker))
+    }
+
+    /// Returns the marker of a truncation removing the entries from the position on,
+    /// which is saved before anything is removed.
+    pub fn truncation(dir: &Path, position: Position) -> Cursor {
+        let mut marker = Cursor::new(dir, TRUNCATION_FILE_NAME);
+        marker.position = position;
+        marker
+    }
+
+    fn new(dir: &Path, fname: &str) -> Cursor {
+        Cursor {
+            dir: dir.to_path_buf(),
+            fname: dir.join(fname),
+            temp: dir.join(format!("{}{}", TEMP_FILE_PREFIX, fname)),
             position: Position {
-                sequence: sequence,
-                read: read,
+                sequence: 0,
+                read: 0,
             },
-        })
+            corrupted: false,
+            saved: false,
+        }
+    }
+
+    // Reads the position from the file, returns false if there is no such file.
+    fn load(&mut self) -> Result<bool> {
+        let file = match OpenOptions::new().read(true).open(&self.fname) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        self.saved = true;
+        match read_position(&file)? {
+            Some((sequence, read)) => self.position = Position { sequence, read },
+            None => self.corrupted = true,
+        }
+
+        Ok(true)
     }
 
+    /// Persists the position atomically: the record is written to a temp file
+    /// which then replaces the cursor file.
     pub fn save(&mut self) -> Result<()> {
-        let mut contents = [0; 32];
+        let mut contents = [0; RECORD_SIZE];
         contents[..16].clone_from_slice(&MAGIC_NUM[..]);
         BigEndian::write_u64(&mut contents[16..24], self.position.sequence);
         BigEndian::write_u64(&mut contents[24..32], self.position.read);
-        write(&self.fname, contents)
+        let crc32 = checksum_ieee(&contents[..LEGACY_RECORD_SIZE]);
+        BigEndian::write_u32(&mut contents[LEGACY_RECORD_SIZE..], crc32);
+
+        let mut file = File::create(&self.temp)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+
+        rename(&self.temp, &self.fname)?;
+        fileext::sync_dir(&self.dir)?;
+
+        self.corrupted = false;
+        self.saved = true;
+        Ok(())
+    }
+
+    /// Removes the cursor file.
+    pub fn remove(&self) -> Result<()> {
+        remove_file(&self.fname)?;
+        fileext::sync_dir(&self.dir)
+    }
+}
+
+/// Returns the names of all the consumers in the dir.
+pub fn names(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for dir_entry in read_dir(dir)? {
+        let fname = dir_entry?.file_name();
+        let name = match fname.to_str() {
+            Some(s) if s.starts_with(CURSOR_FILE_PREFIX) => &s[CURSOR_FILE_PREFIX.len()..],
+            _ => continue,
+        };
+
+        if valid_name(name) {
+            names.push(name.to_owned());
+        }
     }
+
+    names.sort();
+    Ok(names)
 }
 
-fn read_position(f: &File) -> Result<(u64, u64)> {
-    let meta = f.metadata()?;
-    if meta.len() == 0 {
-        return Ok((0, 0));
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Returns None if the record is truncated or damaged.
+fn read_position(f: &File) -> Result<Option<(u64, u64)>> {
+    let size = f.metadata()?.len() as usize;
+    if size != RECORD_SIZE && size != LEGACY_RECORD_SIZE {
+        return Ok(None);
     }
 
-    let mut buf = [0; 32];
-    fileext::read_exact_at(f, &mut buf, 0)?;
+    let mut buf = [0; RECORD_SIZE];
+    fileext::read_exact_at(f, &mut buf[..size], 0)?;
 
     if buf[..16] != MAGIC_NUM {
-        return Err(Error::new(ErrorKind::InvalidData, "invalid magic num"));
+        return Ok(None);
+    }
+
+    if size == RECORD_SIZE
+        && BigEndian::read_u32(&buf[LEGACY_RECORD_SIZE..])
+            != checksum_ieee(&buf[..LEGACY_RECORD_SIZE])
+    {
+        return Ok(None);
     }
 
-    Ok((
+    Ok(Some((
         BigEndian::read_u64(&buf[16..24]),
         BigEndian::read_u64(&buf[24..32]),
-    ))
+    )))
 }
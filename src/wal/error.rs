@@ -0,0 +1,41 @@
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+
+/// EntryTooLarge is the error of writing an entry larger than `Config::max_entry_size`.
+///
+/// It is returned wrapped in an `io::Error` of kind `InvalidInput`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryTooLarge {
+    /// size of the rejected entry
+    pub size: usize,
+
+    /// the configured limit
+    pub limit: usize,
+}
+
+impl EntryTooLarge {
+    // Returns the error if size exceeds the limit, 0 for no limit.
+    pub(crate) fn check(size: usize, limit: usize) -> Result<()> {
+        if limit > 0 && size > limit {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                EntryTooLarge { size, limit },
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for EntryTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "entry of {} bytes exceeds the limit of {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+impl error::Error for EntryTooLarge {}
@@ -0,0 +1,144 @@
+use super::{Entry, Position};
+use segment::Segment;
+use std::collections::VecDeque;
+use std::io::Result;
+
+// entries read from a segment at once
+const BATCH_SIZE: usize = 64;
+
+/// Iter walks through the entries of a wal without moving its cursor or removing any file.
+pub struct Iter<'a> {
+    segments: &'a [Segment],
+    position: Position,
+    check: bool,
+
+    buf: VecDeque<Entry>,
+    done: bool,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(segments: &'a [Segment], position: Position, check: bool) -> Iter<'a> {
+        Iter {
+            segments,
+            position,
+            check,
+            buf: VecDeque::with_capacity(BATCH_SIZE),
+            done: false,
+        }
+    }
+
+    /// Returns the position of the next entry.
+    pub fn position(&self) -> Position {
+        let mut pos = self.position.clone();
+        pos.read -= self.buf.len() as u64;
+        pos
+    }
+
+    fn fill(&mut self) -> Result<()> {
+        for segment in self.segments {
+            if segment.sequence() < self.position.sequence {
+                continue;
+            }
+
+            if segment.sequence() > self.position.sequence {
+                self.position.sequence = segment.sequence();
+                self.position.read = 0;
+            }
+
+            let mut data = Vec::with_capacity(BATCH_SIZE);
+            let read = segment.read_into(
+                self.position.read as usize,
+                BATCH_SIZE,
+                &mut data,
+                self.check,
+            )?;
+
+            if read > 0 {
+                let first = segment.first_lsn() + self.position.read;
+                self.buf.extend(
+                    data.into_iter()
+                        .zip(first..)
+                        .map(|(data, lsn)| Entry { lsn, data }),
+                );
+                self.position.read += read as u64;
+                return Ok(());
+            }
+        }
+
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        if self.buf.is_empty() && !self.done {
+            if let Err(e) = self.fill() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+
+        self.buf.pop_front().map(Ok)
+    }
+}
+
+/// RevIter walks through the entries of a wal backwards, from the latest one to the
+/// oldest one still on disk.
+pub struct RevIter<'a> {
+    segments: &'a [Segment],
+    check: bool,
+
+    // entries left in the last of the segments
+    left: usize,
+    done: bool,
+}
+
+impl<'a> RevIter<'a> {
+    pub(crate) fn new(segments: &'a [Segment], check: bool) -> RevIter<'a> {
+        RevIter {
+            segments,
+            check,
+            left: segments.last().map_or(0, |s| s.len()),
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for RevIter<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        if self.done {
+            return None;
+        }
+
+        while self.left == 0 {
+            if self.segments.len() <= 1 {
+                self.done = true;
+                return None;
+            }
+
+            self.segments = &self.segments[..self.segments.len() - 1];
+            self.left = self.segments[self.segments.len() - 1].len();
+        }
+
+        self.left -= 1;
+        let segment = &self.segments[self.segments.len() - 1];
+
+        let mut data = Vec::with_capacity(1);
+        if let Err(e) = segment.read_into(self.left, 1, &mut data, self.check) {
+            self.done = true;
+            return Some(Err(e));
+        }
+
+        data.pop().map(|data| {
+            Ok(Entry {
+                lsn: segment.first_lsn() + self.left as u64,
+                data,
+            })
+        })
+    }
+}
@@ -0,0 +1,51 @@
+use memmap2::Mmap;
+use segment::Segment;
+use std::io::Result;
+
+/// MappedSegment is a sealed segment mapped into memory, whose entries are borrowed
+/// from the map instead of being copied out of the file.
+///
+/// It is returned by `WAL::map_sealed`, and keeps the wal borrowed so the mapped
+/// file can not be modified or removed while it is alive.
+pub struct MappedSegment<'a> {
+    segment: &'a Segment,
+    map: Mmap,
+    check: bool,
+}
+
+impl<'a> MappedSegment<'a> {
+    pub(crate) fn new(segment: &'a Segment, check: bool) -> Result<MappedSegment<'a>> {
+        let map = segment.map()?;
+        Ok(MappedSegment {
+            segment,
+            map,
+            check,
+        })
+    }
+
+    /// Returns the lsn of the first entry in the segment.
+    pub fn first_lsn(&self) -> u64 {
+        self.segment.first_lsn()
+    }
+
+    /// Returns the number of entries in the segment.
+    pub fn len(&self) -> usize {
+        self.segment.len()
+    }
+
+    /// Returns true if the segment holds no entry.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the entry at the index of the segment.
+    pub fn get(&self, index: usize) -> Result<&[u8]> {
+        self.segment.mapped_entry(&self.map, index, self.check)
+    }
+
+    /// Returns an iterator over the entries of the segment along with their lsns.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(u64, &[u8])>> {
+        let first = self.first_lsn();
+        (0..self.len()).map(move |i| self.get(i).map(|data| (first + i as u64, data)))
+    }
+}
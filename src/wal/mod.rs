@@ -1,12 +1,46 @@
-use config::Config;
-use segment::Segment;
+use config::{Config, SyncPolicy};
+use fileext;
+use segment::{Segment, BATCH, BATCH_END};
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs;
 use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+mod consumer;
+pub use self::consumer::Consumer;
 
 mod cursor;
 use self::cursor::Cursor;
+pub use self::cursor::Position;
+
+mod error;
+pub use self::error::EntryTooLarge;
+
+mod iter;
+pub use self::iter::{Iter, RevIter};
+
+mod mapped;
+pub use self::mapped::MappedSegment;
+
+mod reader;
+pub use self::reader::EntryReader;
+
+mod recovery;
+pub use self::recovery::RecoveryReport;
+
+mod shared;
+pub use self::shared::SharedWAL;
+
+mod signal;
+use self::signal::Signal;
+
+mod writer;
+pub use self::writer::EntryWriter;
 
 #[cfg(test)]
 mod tests;
@@ -16,171 +50,972 @@ pub struct WAL {
     cfg: Config,
     dir: PathBuf,
     cursor: Cursor,
+    consumers: BTreeMap<String, Cursor>,
 
     next_sequence: u64,
 
     segments: Vec<Segment>,
+
+    unsynced: usize,
+    last_sync: Instant,
+
+    signal: Arc<Signal>,
+
+    // set once a failed atomic batch could not be rolled back, writes are refused then
+    broken: bool,
+
+    // marker of a truncation which failed halfway, finished before the next write
+    truncation: Option<Cursor>,
+    // bumped by every truncation, so the commit tokens taken before it are rejected
+    truncations: u64,
+
+    // reused by read_with
+    scratch: Vec<u8>,
+    ranges: Vec<Range<usize>>,
+    // reused by read_into_buf for the overheads of the entries read
+    table: Vec<u8>,
+
+    // number of sync calls, lets the tests tell how the entries were grouped
+    #[cfg(test)]
+    syncs: usize,
+}
+
+/// Entry is an entry read from the wal along with its lsn.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// log sequence number of the entry
+    pub lsn: u64,
+
+    /// content of the entry
+    pub data: Vec<u8>,
+}
+
+/// Location of an entry, either its position or its lsn.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    /// position of the entry
+    Position(Position),
+
+    /// lsn of the entry
+    Lsn(u64),
+}
+
+impl From<Position> for Location {
+    fn from(pos: Position) -> Location {
+        Location::Position(pos)
+    }
+}
+
+impl From<u64> for Location {
+    fn from(lsn: u64) -> Location {
+        Location::Lsn(lsn)
+    }
+}
+
+/// CommitToken marks the end of a batch returned by `WAL::read_pending`.
+#[must_use]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitToken {
+    position: Position,
+    truncations: u64,
+}
+
+impl CommitToken {
+    /// Returns the position the cursor moves to once the token is committed.
+    pub fn position(&self) -> &Position {
+        &self.position
+    }
 }
 
 impl WAL {
     /// Opens a wal with given dir.
     pub fn open<S: AsRef<OsStr> + ?Sized>(dir: &S, cfg: Config) -> Result<WAL> {
+        WAL::open_with_report(dir, cfg).map(|(wal, _)| wal)
+    }
+
+    /// Opens a wal with given dir, and reports the torn entries truncated from the last segment.
+    pub fn open_with_report<S: AsRef<OsStr> + ?Sized>(
+        dir: &S,
+        cfg: Config,
+    ) -> Result<(WAL, RecoveryReport)> {
         let p = Path::new(dir);
         if !p.exists() {
-            fs::create_dir_all(&p)?;
+            fs::create_dir_all(p)?;
         }
 
         if !p.is_dir() {
-            return Err(Error::new(ErrorKind::Other, "expecting a directory"));
+            return Err(Error::other("expecting a directory"));
         }
 
         let dir = p.to_path_buf();
 
-        let mut cursor = Cursor::open(&dir)?;
+        let mut cursor = Cursor::open(&dir, None)?;
+        let mut consumers = BTreeMap::new();
+        for name in cursor::names(&dir)? {
+            let consumer = Cursor::open(&dir, Some(&name))?;
+            consumers.insert(name, consumer);
+        }
+
+        // segments before all the valid cursors have been consumed already, the default
+        // one only counts once it is used
+        let consumed = consumers
+            .values()
+            .chain(Some(&cursor).filter(|c| c.saved))
+            .filter(|c| !c.corrupted)
+            .map(|c| c.position.sequence)
+            .min()
+            .unwrap_or(0);
+
+        // the segments an interrupted truncation has not removed yet are left out
+        let truncation = Cursor::open_truncation(&dir)?;
+        let mut sequences = Segment::sequences(&dir)?;
+        if let Some(ref marker) = truncation {
+            sequences.retain(|&seq| seq <= marker.position.sequence);
+        }
+
+        // the last segment lacks its head if a crash interrupted its creation
+        let mut report = RecoveryReport::default();
+        if let Some(&last) = sequences.last() {
+            if Segment::torn_head(&dir, last)? {
+                fs::remove_file(dir.join(Segment::filename(last)))?;
+                fileext::sync_dir(&dir)?;
+                sequences.pop();
+                report.segments_removed += 1;
+            }
+        }
 
-        let mut read_sequence = cursor.position.sequence;
         let mut segments: Vec<Segment> = Vec::with_capacity(10);
-        loop {
-            match Segment::open(&dir, read_sequence, cfg.entry_per_segment, false) {
-                Ok(s) => segments.push(s),
-                Err(ref e) if e.kind() == ErrorKind::NotFound => break,
-                Err(e) => return Err(e),
+        for (i, &seq) in sequences.iter().enumerate() {
+            let mut segment = Segment::open(&dir, seq, cfg.entry_per_segment, 0, false)?;
+
+            // the last one is kept anyway to carry on the lsn
+            if seq < consumed && i + 1 < sequences.len() {
+                segment.destory();
+                continue;
+            }
+
+            if let Some(prev) = segments.last() {
+                segment.derive_first_lsn(prev.next_lsn());
             }
 
-            read_sequence += 1;
+            segments.push(segment);
         }
 
-        match segments.first() {
-            Some(s) => if s.len() < cursor.position.read as usize {
-                cursor.position.read = 0;
-            },
-            None => {
-                cursor.position.sequence = 0;
-                cursor.position.read = 0;
-            }
+        if let Some(marker) = truncation {
+            cut(&dir, &mut segments, &marker.position)?;
+            marker.remove()?;
         }
 
-        Ok(WAL {
-            cfg: cfg,
-            dir: dir,
-            cursor: cursor,
-            next_sequence: read_sequence,
-            segments: segments,
-        })
+        if let Some(s) = segments.last_mut() {
+            let (discarded, truncated) = s.recover()?;
+            report.entries_discarded += discarded;
+            report.bytes_truncated += truncated;
+        }
+
+        let (discarded, truncated) = discard_unended_batch(&mut segments)?;
+        report.entries_discarded += discarded;
+        report.bytes_truncated += truncated;
+        report.segment = segments.last().map(|s| s.sequence());
+
+        // legacy segments take their lsns from the segment after them, which is created
+        // right away if there is none, so they keep the same lsns once older ones are gone
+        if let Some(last) = segments.last().filter(|s| s.is_legacy()) {
+            let (sequence, lsn) = (last.sequence() + 1, last.next_lsn());
+            segments.push(Segment::open(
+                &dir,
+                sequence,
+                cfg.entry_per_segment,
+                lsn,
+                true,
+            )?);
+            fileext::sync_dir(&dir)?;
+        }
+        derive_legacy_lsns(&mut segments);
+
+        // limited after recovery, which would discard the oversized entries otherwise
+        for s in segments.iter_mut() {
+            s.set_max_entry_size(cfg.max_entry_size);
+        }
+
+        repair(&mut cursor, &segments)?;
+        for consumer in consumers.values_mut() {
+            repair(consumer, &segments)?;
+        }
+
+        let next_sequence = match segments.last() {
+            Some(s) => s.sequence() + 1,
+            None => cursor.position.sequence,
+        };
+
+        let wal = WAL {
+            cfg,
+            dir,
+            cursor,
+            consumers,
+            next_sequence,
+            segments,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            signal: Arc::new(Signal::default()),
+            broken: false,
+            truncation: None,
+            truncations: 0,
+            scratch: Vec::new(),
+            ranges: Vec::new(),
+            table: Vec::new(),
+            #[cfg(test)]
+            syncs: 0,
+        };
+
+        Ok((wal, report))
     }
 
-    /// Write bytes to wal
-    pub fn write(&mut self, data: &[u8]) -> Result<()> {
-        self.try_allocate(1)?;
+    /// Write bytes to wal, returns the lsn of the entry.
+    pub fn write(&mut self, data: &[u8]) -> Result<u64> {
+        EntryTooLarge::check(data.len(), self.cfg.max_entry_size)?;
+        self.try_allocate(&[data])?;
         let segment = self.segments.last_mut().unwrap();
+        let lsn = segment.next_lsn();
         segment.write(data)?;
 
-        Ok(())
+        self.after_write(1)?;
+        Ok(lsn)
+    }
+
+    /// Writes multiple entries to wal, returns the lsns of the entries.
+    pub fn batch_write(&mut self, data: &[&[u8]]) -> Result<Range<u64>> {
+        let lsns = self.append(data, false)?;
+        self.after_write(data.len())?;
+        Ok(lsns)
+    }
+
+    /// Writes multiple entries to wal as a whole, returns the lsns of the entries.
+    ///
+    /// If the write fails or the process crashes in the middle, none of the entries
+    /// is kept, even if they are spread over several segments. Should the written
+    /// entries fail to be removed, the wal refuses any further write until it is
+    /// opened again, which discards them.
+    pub fn atomic_batch_write(&mut self, data: &[&[u8]]) -> Result<Range<u64>> {
+        let lsns = self.rollback_on_error(|wal| wal.append(data, true))?;
+        self.after_write(data.len())?;
+        Ok(lsns)
     }
 
-    /// Writes multiple entries to wal.
-    pub fn batch_write(&mut self, mut data: &[&[u8]]) -> Result<()> {
+    /// Returns a writer streaming a single entry into wal, which is only written once it
+    /// is finished.
+    pub fn entry_writer(&mut self) -> Result<EntryWriter<'_>> {
+        EntryWriter::new(self)
+    }
+
+    fn append(&mut self, mut data: &[&[u8]], atomic: bool) -> Result<Range<u64>> {
+        for entry in data {
+            EntryTooLarge::check(entry.len(), self.cfg.max_entry_size)?;
+        }
+
+        let first = self.next_lsn();
+        let lsns = first..first + data.len() as u64;
+        let flags = if atomic { BATCH } else { 0 };
+
         while !data.is_empty() {
-            let space = self.try_allocate(data.len())?;
+            let space = self.try_allocate(data)?;
+            let last_flags = if atomic && space == data.len() {
+                BATCH | BATCH_END
+            } else {
+                flags
+            };
 
             let segment = self.segments.last_mut().unwrap();
-            let written = segment.batch_write(&data[0..space])?;
+            let written = segment.batch_write(&data[0..space], flags, last_flags)?;
             data = &data[written..];
         }
 
-        Ok(())
+        Ok(lsns)
     }
 
-    fn try_allocate(&mut self, n: usize) -> Result<(usize)> {
-        match self.segments.last_mut() {
-            Some(ref s) if s.space() > 0 => {
-                let space = s.space();
+    // Runs the write, and removes whatever it has written if it fails.
+    fn rollback_on_error<T, F>(&mut self, write: F) -> Result<T>
+    where
+        F: FnOnce(&mut WAL) -> Result<T>,
+    {
+        let segments = self.segments.len();
+        let len = self.segments.last().map_or(0, |s| s.len());
+        let next_sequence = self.next_sequence;
 
-                return if space > n { Ok(n) } else { Ok(space) };
+        write(self).map_err(|e| {
+            // the unended batch left on disk is dropped on the next open, as long as
+            // nothing is written after it
+            match self.rollback(segments, len, next_sequence) {
+                Ok(()) => e,
+                Err(e) => {
+                    self.broken = true;
+                    e
+                }
             }
-            Some(ss) => {
-                let _ = ss.flush();
+        })
+    }
+
+    // Removes the segments and entries written after the given state.
+    fn rollback(&mut self, segments: usize, len: usize, next_sequence: u64) -> Result<()> {
+        let count = self.segments.len();
+        while self.segments.len() > segments {
+            self.segments.pop().unwrap().destory();
+        }
+
+        if self.segments.len() < count {
+            fileext::sync_dir(&self.dir)?;
+        }
+
+        if let Some(s) = self.segments.last_mut() {
+            s.truncate(len)?;
+        }
+
+        self.next_sequence = next_sequence;
+        Ok(())
+    }
+
+    /// Flushes all written entries to disk, regardless of the sync policy.
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(s) = self.segments.last_mut() {
+            s.flush()?;
+        }
+
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        #[cfg(test)]
+        {
+            self.syncs += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the written entries if the sync policy asks for it, returns whether it did.
+    ///
+    /// Writes only check the policy as they happen, so with `SyncPolicy::Interval` the
+    /// last entries of a burst stay unsynced until the next write. Calling this every
+    /// interval, e.g. from a timer thread, bounds how long they stay so.
+    pub fn sync_if_due(&mut self) -> Result<bool> {
+        if self.unsynced == 0 || !self.sync_due() {
+            return Ok(false);
+        }
+
+        self.sync()?;
+        Ok(true)
+    }
+
+    fn sync_due(&self) -> bool {
+        match self.cfg.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Entries(limit) => self.unsynced >= limit,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        }
+    }
+
+    fn after_write(&mut self, n: usize) -> Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+
+        self.signal.notify();
+        self.unsynced += n;
+
+        if self.sync_due() {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    // Makes sure the last segment takes the leading entries of data, rolls to a new one
+    // if it is full or expired. Returns the number of entries it takes.
+    fn try_allocate(&mut self, data: &[&[u8]]) -> Result<usize> {
+        if self.broken {
+            return Err(Error::other(
+                "wal is broken by a failed rollback, it must be opened again",
+            ));
+        }
+
+        // the files left by the truncation are removed before a new segment reuses them
+        self.finish_truncation()?;
+
+        if let Some(s) = self.segments.last_mut() {
+            // an expired segment is sealed, unless there is nothing in it
+            let expired = s.len() > 0 && self.cfg.max_segment_age.is_some_and(|age| s.age() >= age);
+            let n = if expired {
+                0
+            } else {
+                fit_segment(s, data, self.cfg.max_segment_bytes)
+            };
+            if n > 0 {
+                return Ok(n);
             }
-            None => {}
+
+            s.flush()?;
         }
 
-        let new_seg = Segment::open(
+        let mut new_seg = Segment::open(
             &self.dir,
             self.next_sequence,
             self.cfg.entry_per_segment,
+            self.next_lsn(),
             true,
         )?;
-        let space = new_seg.space();
+        fileext::sync_dir(&self.dir)?;
+        new_seg.set_max_entry_size(self.cfg.max_entry_size);
+        let n = fit_segment(&new_seg, data, self.cfg.max_segment_bytes);
         self.next_sequence += 1;
         self.segments.push(new_seg);
 
-        if space > n {
-            Ok(n)
-        } else {
-            Ok(space)
-        }
+        Ok(n)
     }
 
     /// Read N entries from wal.
-    pub fn read(&mut self, mut n: usize) -> Result<Vec<Vec<u8>>> {
+    pub fn read(&mut self, n: usize) -> Result<Vec<Vec<u8>>> {
+        let (result, token) = self.read_pending(n)?;
+        self.commit(token)?;
+
+        Ok(result)
+    }
+
+    /// Read at most N entries from wal, stopping before their total size exceeds max_bytes.
+    ///
+    /// The next entry is always returned even if it is larger than max_bytes on its own.
+    pub fn read_bytes(&mut self, max_entries: usize, max_bytes: usize) -> Result<Vec<Vec<u8>>> {
+        let n = self.fit(&self.cursor.position, max_entries, max_bytes)?;
+        self.read(n)
+    }
+
+    /// Read N entries from wal and passes each of them to f, returns the number of entries read.
+    ///
+    /// The entries are read into buffers kept by the wal, which are reused by the later
+    /// calls, so reading allocates nothing once they are large enough.
+    pub fn read_with<F: FnMut(&[u8])>(&mut self, n: usize, mut f: F) -> Result<usize> {
+        let mut buf = mem::take(&mut self.scratch);
+        let mut ranges = mem::take(&mut self.ranges);
+        buf.clear();
+        ranges.clear();
+
+        let res = self.read_into_buf(n, &mut buf, &mut ranges);
+        if res.is_ok() {
+            for range in ranges.iter() {
+                f(&buf[range.clone()]);
+            }
+        }
+
+        self.scratch = buf;
+        self.ranges = ranges;
+        res
+    }
+
+    /// Read N entries from wal and appends them to buf, with the range of each entry in
+    /// buf appended to ranges. Returns the number of entries read.
+    pub fn read_into_buf(
+        &mut self,
+        n: usize,
+        buf: &mut Vec<u8>,
+        ranges: &mut Vec<Range<usize>>,
+    ) -> Result<usize> {
+        let (len, count) = (buf.len(), ranges.len());
+        let mut table = mem::take(&mut self.table);
+        let res = self
+            .traverse(&self.cursor.position, n, |segment, start, n| {
+                segment.read_into_buf(start, n, buf, ranges, &mut table, self.cfg.check_crc32)
+            })
+            .and_then(|position| self.commit(self.token(position)));
+        self.table = table;
+
+        if let Err(e) = res {
+            buf.truncate(len);
+            ranges.truncate(count);
+            return Err(e);
+        }
+
+        Ok(ranges.len() - count)
+    }
+
+    /// Returns the next N entries without consuming them, together with a token which
+    /// consumes them once passed to `commit`.
+    pub fn read_pending(&self, n: usize) -> Result<(Vec<Vec<u8>>, CommitToken)> {
         let mut result: Vec<Vec<u8>> = Vec::with_capacity(n);
 
-        let mut seg_read: usize = 0;
-        let mut seg_finished: usize = 0;
-        let start_pos = self.cursor.position.clone();
-        let mut start: usize = self.cursor.position.read as usize;
-
-        while n > 0 {
-            let segment = match self.segments.get(seg_read) {
-                Some(s) if s.len() > start => s,
-                Some(_) => {
-                    seg_read += 1;
-                    seg_finished += 1;
-                    start = 0;
-                    continue;
-                }
-                None => break,
+        let position = self.collect(&self.cursor.position, n, &mut result)?;
+
+        Ok((result, self.token(position)))
+    }
+
+    /// Consumes the entries returned along with the token.
+    ///
+    /// Committing a token which is behind the cursor is a no-op, while a token taken
+    /// before `truncate_after` is rejected.
+    pub fn commit(&mut self, token: CommitToken) -> Result<()> {
+        self.check_token(&token)?;
+        if token.position <= self.cursor.position {
+            return Ok(());
+        }
+
+        let position = self.resolve(token.position.into())?;
+        self.move_cursor(None, position)
+    }
+
+    // Returns a token which moves a cursor to the position once committed.
+    fn token(&self, position: Position) -> CommitToken {
+        CommitToken {
+            position,
+            truncations: self.truncations,
+        }
+    }
+
+    // Rejects a token taken before the last truncation.
+    fn check_token(&self, token: &CommitToken) -> Result<()> {
+        if token.truncations != self.truncations {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "commit token is outdated by a truncation",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next N entries like `read`, but leaves the cursor untouched.
+    pub fn peek(&self, n: usize) -> Result<Vec<Vec<u8>>> {
+        let mut result: Vec<Vec<u8>> = Vec::with_capacity(n);
+
+        self.collect(&self.cursor.position, n, &mut result)?;
+
+        Ok(result)
+    }
+
+    // Reads up to N entries after the given position, returns the position of the last one read.
+    fn collect(&self, from: &Position, n: usize, data: &mut Vec<Vec<u8>>) -> Result<Position> {
+        self.traverse(from, n, |segment, start, n| {
+            segment.read_into(start, n, data, self.cfg.check_crc32)
+        })
+    }
+
+    // Calls read with the segments after the given position, until N entries are read.
+    fn traverse<F>(&self, from: &Position, mut n: usize, mut read: F) -> Result<Position>
+    where
+        F: FnMut(&Segment, usize, usize) -> Result<usize>,
+    {
+        let mut pos = from.clone();
+
+        for segment in &self.segments {
+            if n == 0 {
+                break;
+            }
+
+            if segment.sequence() < pos.sequence {
+                continue;
+            }
+
+            let start = if segment.sequence() == pos.sequence {
+                pos.read as usize
+            } else {
+                0
             };
 
-            let read = segment.read_into(start, n, &mut result, self.cfg.check_crc32)?;
-            start += read;
+            let read = read(segment, start, n)?;
+            if read == 0 {
+                continue;
+            }
+
+            pos.sequence = segment.sequence();
+            pos.read = (start + read) as u64;
             n -= read;
-            self.cursor.position.sequence = segment.sequence();
-            self.cursor.position.read = start as u64;
+        }
 
-            if n > 0 {
-                if segment.space() > 0 {
-                    break;
-                }
+        Ok(pos)
+    }
+
+    // Returns how many of the N entries after the given position fit in the byte budget,
+    // at least one unless N is 0.
+    fn fit(&self, from: &Position, n: usize, max_bytes: usize) -> Result<usize> {
+        let mut count: usize = 0;
+        let mut budget = max_bytes;
 
-                start = 0;
-                seg_read += 1;
-                seg_finished += 1;
+        for segment in &self.segments {
+            if count == n {
+                break;
+            }
+
+            if segment.sequence() < from.sequence {
+                continue;
             }
+
+            let start = if segment.sequence() == from.sequence {
+                from.read as usize
+            } else {
+                0
+            };
+
+            let wanted = (n - count).min(segment.len().saturating_sub(start));
+            let (fitted, bytes) = segment.fit(start, wanted, budget)?;
+            count += fitted;
+            budget -= bytes;
+
+            if fitted < wanted {
+                break;
+            }
+        }
+
+        Ok(count.max(1).min(n))
+    }
+
+    // Moves the cursor of the consumer, and removes the segments left behind by all consumers.
+    fn move_cursor(&mut self, consumer: Option<&str>, pos: Position) -> Result<()> {
+        let cursor = match consumer {
+            Some(name) => self
+                .consumers
+                .get_mut(name)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "consumer not found"))?,
+            None => &mut self.cursor,
+        };
+
+        let prev = mem::replace(&mut cursor.position, pos);
+        if let Err(e) = cursor.save() {
+            cursor.position = prev;
+            return Err(e);
+        }
+
+        self.release()
+    }
+
+    // Removes the segments which every consumer has moved past, the default cursor
+    // only counts once it is used.
+    fn release(&mut self) -> Result<()> {
+        let min = match self
+            .consumers
+            .values()
+            .chain(Some(&self.cursor).filter(|c| c.saved))
+            .map(|c| c.position.sequence)
+            .min()
+        {
+            Some(min) => min,
+            None => return Ok(()),
+        };
+
+        let count = self.segments.len();
+        while self.segments.len() > 1 && self.segments[0].sequence() < min {
+            self.segments.remove(0).destory();
+        }
+
+        if self.segments.len() < count {
+            fileext::sync_dir(&self.dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read N entries from wal along with their lsns.
+    pub fn read_entries(&mut self, n: usize) -> Result<Vec<Entry>> {
+        let first = self.lsn_at(&self.cursor.position);
+        let data = self.read(n)?;
+
+        Ok(data
+            .into_iter()
+            .zip(first..)
+            .map(|(data, lsn)| Entry { lsn, data })
+            .collect())
+    }
+
+    /// Returns the lsn of the oldest entry on disk.
+    pub fn first_lsn(&self) -> Option<u64> {
+        let first = self.segments.first()?.first_lsn();
+        if first < self.next_lsn() {
+            Some(first)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the lsn of the latest written entry.
+    pub fn last_lsn(&self) -> Option<u64> {
+        self.first_lsn().map(|_| self.next_lsn() - 1)
+    }
+
+    /// Returns the entry with the given lsn, if it is still on disk.
+    pub fn get(&self, lsn: u64) -> Result<Option<Vec<u8>>> {
+        let (i, index) = match self.locate(lsn) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let mut data = Vec::with_capacity(1);
+        self.segments[i].read_into(index, 1, &mut data, self.cfg.check_crc32)?;
+
+        Ok(data.pop())
+    }
+
+    /// Returns the entries with lsn in `[from, to)`.
+    ///
+    /// Entries not written yet are left out, while an error is returned if `from`
+    /// is older than any entry on disk.
+    pub fn read_range(&self, from: u64, to: u64) -> Result<Vec<Entry>> {
+        let to = to.min(self.next_lsn());
+        if from >= to {
+            return Ok(Vec::new());
         }
 
-        if seg_finished > 0 {
-            for _ in 0..seg_finished {
-                self.segments.remove(0).destory();
+        let (first, mut index) = self
+            .locate(from)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "lsn not found"))?;
+
+        let n = (to - from) as usize;
+        let mut data = Vec::with_capacity(n);
+        for segment in &self.segments[first..] {
+            if data.len() >= n {
+                break;
             }
+
+            segment.read_into(index, n - data.len(), &mut data, self.cfg.check_crc32)?;
+            index = 0;
         }
 
-        if self.cursor.position != start_pos {
-            let _ = self.cursor.save();
+        Ok(data
+            .into_iter()
+            .zip(from..)
+            .map(|(data, lsn)| Entry { lsn, data })
+            .collect())
+    }
+
+    /// Returns a reader streaming the entry at the given location out of its segment file.
+    pub fn entry_reader<L: Into<Location>>(&self, location: L) -> Result<EntryReader<'_>> {
+        let position = self.resolve(location.into())?;
+        let (i, index) = self
+            .locate(self.lsn_at(&position))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "entry not found"))?;
+
+        EntryReader::new(&self.segments[i], index, self.cfg.check_crc32)
+    }
+
+    // Returns the index of the segment holding the lsn, and the index of the entry in it.
+    fn locate(&self, lsn: u64) -> Option<(usize, usize)> {
+        let i = self.segments.partition_point(|s| s.first_lsn() <= lsn);
+        if i == 0 || lsn >= self.segments[i - 1].next_lsn() {
+            return None;
         }
 
-        Ok(result)
+        let segment = &self.segments[i - 1];
+        Some((i - 1, (lsn - segment.first_lsn()) as usize))
+    }
+
+    fn next_lsn(&self) -> u64 {
+        self.segments.last().map_or(0, |s| s.next_lsn())
+    }
+
+    // Returns the lsn of the entry at the given position.
+    fn lsn_at(&self, pos: &Position) -> u64 {
+        for segment in &self.segments {
+            if segment.sequence() == pos.sequence {
+                return segment.first_lsn() + pos.read;
+            }
+
+            if segment.sequence() > pos.sequence {
+                return segment.first_lsn();
+            }
+        }
+
+        self.next_lsn()
+    }
+
+    /// Returns an iterator over the unread entries, without consuming them.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(
+            &self.segments,
+            self.cursor.position.clone(),
+            self.cfg.check_crc32,
+        )
+    }
+
+    /// Returns an iterator over the entries still on disk from the latest one backwards,
+    /// without moving the cursor.
+    pub fn iter_rev(&self) -> RevIter<'_> {
+        RevIter::new(&self.segments, self.cfg.check_crc32)
+    }
+
+    /// Returns an iterator over the entries still on disk, starting at the given position.
+    pub fn iter_from<L: Into<Location>>(&self, location: L) -> Result<Iter<'_>> {
+        let position = self.resolve(location.into())?;
+
+        Ok(Iter::new(&self.segments, position, self.cfg.check_crc32))
+    }
+
+    /// Maps the sealed segments into memory, so their entries can be read without copies.
+    ///
+    /// A segment is sealed once it is full or a newer one is created, the one still
+    /// being written is left out.
+    pub fn map_sealed(&self) -> Result<Vec<MappedSegment<'_>>> {
+        let sealed = match self.segments.last() {
+            Some(last) if last.space() > 0 => &self.segments[..self.segments.len() - 1],
+            _ => &self.segments[..],
+        };
+
+        sealed
+            .iter()
+            .map(|s| MappedSegment::new(s, self.cfg.check_crc32))
+            .collect()
+    }
+
+    /// Moves the cursor to any entry still on disk, so the following reads start from it.
+    pub fn seek<L: Into<Location>>(&mut self, location: L) -> Result<()> {
+        let position = self.resolve(location.into())?;
+        self.move_cursor(None, position)
+    }
+
+    /// Moves the cursor back to the oldest entry on disk.
+    pub fn rewind(&mut self) -> Result<()> {
+        let position = self.oldest();
+        self.move_cursor(None, position)
+    }
+
+    /// Removes all the entries after the given one, which is kept, as a raft follower
+    /// does with a conflicting suffix of its log.
+    ///
+    /// Once it returns the removed entries never come back, and a crash in the middle
+    /// is finished on the next open. Cursors after the kept entry are moved back to the
+    /// end of the wal.
+    pub fn truncate_after<L: Into<Location>>(&mut self, location: L) -> Result<()> {
+        let lsn = match location.into() {
+            Location::Lsn(lsn) => lsn,
+            location => {
+                let position = self.resolve(location)?;
+                self.lsn_at(&position)
+            }
+        };
+
+        if lsn.saturating_add(1) >= self.next_lsn() {
+            return Ok(());
+        }
+
+        if self.locate(lsn).is_none() {
+            return Err(Error::new(ErrorKind::NotFound, "lsn not found"));
+        }
+
+        // recorded before anything is removed, so a crash in the middle is finished on open
+        let (i, index) = self.locate(lsn + 1).unwrap();
+        let position = Position {
+            sequence: self.segments[i].sequence(),
+            read: index as u64,
+        };
+        self.truncations += 1;
+        let mut marker = Cursor::truncation(&self.dir, position);
+        marker.save()?;
+        self.truncation = Some(marker);
+
+        // the later segments are dropped at once, so nothing is appended to them
+        self.segments.truncate(i + 1);
+        self.next_sequence = self.segments[i].sequence() + 1;
+        self.finish_truncation()
+    }
+
+    // Cuts the segments as the marker of the unfinished truncation records, then removes it.
+    fn finish_truncation(&mut self) -> Result<()> {
+        let marker = match self.truncation {
+            Some(ref marker) => marker,
+            None => return Ok(()),
+        };
+
+        cut(&self.dir, &mut self.segments, &marker.position)?;
+
+        repair(&mut self.cursor, &self.segments)?;
+        for consumer in self.consumers.values_mut() {
+            repair(consumer, &self.segments)?;
+        }
+
+        marker.remove()?;
+        self.truncation = None;
+        Ok(())
+    }
+
+    /// Opens the consumer with the given name, which has its own cursor persisted in
+    /// the `cursor.<name>` file.
+    ///
+    /// A new consumer starts at the oldest entry on disk, segments are only removed
+    /// once every consumer has moved past them. The default cursor used by `WAL::read`
+    /// counts as one too, as soon as it is moved once.
+    pub fn open_consumer(&mut self, name: &str) -> Result<Consumer<'_>> {
+        if !self.consumers.contains_key(name) {
+            let mut cursor = Cursor::open(&self.dir, Some(name))?;
+            cursor.position = self.oldest();
+            cursor.save()?;
+            self.consumers.insert(name.to_owned(), cursor);
+        }
+
+        Ok(Consumer::new(self, name.to_owned()))
+    }
+
+    /// Unregisters the consumer and removes its cursor file.
+    pub fn remove_consumer(&mut self, name: &str) -> Result<()> {
+        if let Some(cursor) = self.consumers.remove(name) {
+            cursor.remove()?;
+            return self.release();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the names of all the registered consumers.
+    pub fn consumers(&self) -> Vec<String> {
+        self.consumers.keys().cloned().collect()
+    }
+
+    // Returns the position of the oldest entry on disk.
+    fn oldest(&self) -> Position {
+        Position {
+            sequence: self
+                .segments
+                .first()
+                .map_or(self.next_sequence, |s| s.sequence()),
+            read: 0,
+        }
+    }
+
+    // Checks the location is on disk and returns its position.
+    fn resolve(&self, location: Location) -> Result<Position> {
+        match location {
+            Location::Position(pos) => {
+                match self.segments.iter().find(|s| s.sequence() == pos.sequence) {
+                    Some(s) if s.len() >= pos.read as usize => Ok(pos),
+                    _ => Err(Error::new(ErrorKind::NotFound, "position not found")),
+                }
+            }
+            Location::Lsn(lsn) => {
+                if let Some((i, index)) = self.locate(lsn) {
+                    return Ok(Position {
+                        sequence: self.segments[i].sequence(),
+                        read: index as u64,
+                    });
+                }
+
+                match self.segments.last() {
+                    Some(s) if lsn == s.next_lsn() => Ok(Position {
+                        sequence: s.sequence(),
+                        read: s.len() as u64,
+                    }),
+                    _ => Err(Error::new(ErrorKind::NotFound, "lsn not found")),
+                }
+            }
+        }
     }
 
     /// Returns entry number in the wal.
     pub fn len(&self) -> usize {
+        self.remaining(&self.cursor.position)
+    }
+
+    // Returns the number of entries after the position.
+    fn remaining(&self, pos: &Position) -> usize {
         let mut size: usize = 0;
 
         for segment in &self.segments {
-            let num = if segment.sequence() == self.cursor.position.sequence {
-                segment.len() - self.cursor.position.read as usize
-            } else {
+            let num = if segment.sequence() == pos.sequence {
+                segment.len().saturating_sub(pos.read as usize)
+            } else if segment.sequence() > pos.sequence {
                 segment.len()
+            } else {
+                0
             };
 
             size += num;
@@ -188,4 +1023,132 @@ impl WAL {
 
         size
     }
+
+    /// Returns true if there is no entry left to read.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Returns how many of the leading entries of data the segment can take, within the
+// entry limit and max_bytes. An empty segment takes at least one entry.
+fn fit_segment(segment: &Segment, data: &[&[u8]], max_bytes: usize) -> usize {
+    let n = data.len().min(segment.space());
+    if max_bytes == 0 || n == 0 {
+        return n;
+    }
+
+    let mut size = segment.data_size() as usize;
+    let mut fitted = 0;
+    for entry in &data[..n] {
+        size += entry.len();
+        if size > max_bytes && (fitted > 0 || segment.len() > 0) {
+            break;
+        }
+
+        fitted += 1;
+    }
+
+    fitted
+}
+
+// Derives the first lsn of every legacy segment backwards from the segment after it.
+fn derive_legacy_lsns(segments: &mut [Segment]) {
+    for i in (1..segments.len()).rev() {
+        let next = segments[i].first_lsn();
+        let prev = &mut segments[i - 1];
+        let first = next.saturating_sub(prev.len() as u64);
+        prev.derive_first_lsn(first);
+    }
+}
+
+// Discards the entries of an atomic batch left unended at the tail by a crash, returns
+// the number of discarded entries and truncated bytes.
+fn discard_unended_batch(segments: &mut Vec<Segment>) -> Result<(usize, u64)> {
+    // the batch starts in segments[start], and takes up all the later ones
+    let mut start = segments.len();
+    let mut tail = 0;
+    while start > 0 {
+        start -= 1;
+        tail = segments[start].unended_batch()?;
+        if tail < segments[start].len() {
+            break;
+        }
+    }
+
+    let later: usize = segments.iter().skip(start + 1).map(|s| s.len()).sum();
+    if tail + later == 0 {
+        return Ok((0, 0));
+    }
+
+    let mut discarded = tail;
+    let mut truncated = 0;
+
+    // newest first, so a crash in the middle leaves the batch unended still
+    while segments.len() > start + 1 {
+        let mut s = segments.pop().unwrap();
+        discarded += s.len();
+        truncated += s.data_size();
+        s.destory();
+    }
+
+    let s = &mut segments[start];
+    let len = s.len() - tail;
+    truncated += s.truncate(len)?;
+
+    Ok((discarded, truncated))
+}
+
+// Cuts the last segment at the position of a truncation if it is the one, and removes
+// the segment files after it.
+fn cut(dir: &Path, segments: &mut [Segment], pos: &Position) -> Result<()> {
+    if let Some(s) = segments.last_mut() {
+        if s.sequence() == pos.sequence {
+            s.truncate(pos.read as usize)?;
+        }
+    }
+
+    for seq in Segment::sequences(dir)?.into_iter().rev() {
+        if seq > pos.sequence {
+            fs::remove_file(dir.join(Segment::filename(seq)))?;
+        }
+    }
+
+    fileext::sync_dir(dir)
+}
+
+// Moves the cursor onto the segments on disk, and rewinds a corrupted one.
+fn repair(cursor: &mut Cursor, segments: &[Segment]) -> Result<()> {
+    let start_pos = cursor.position.clone();
+
+    match segments.first() {
+        Some(s) if cursor.corrupted || s.sequence() > cursor.position.sequence => {
+            cursor.position.sequence = s.sequence();
+            cursor.position.read = 0;
+        }
+        Some(_) => match segments
+            .iter()
+            .rev()
+            .find(|s| s.sequence() <= cursor.position.sequence)
+        {
+            Some(s) if s.sequence() < cursor.position.sequence => {
+                cursor.position.sequence = s.sequence();
+                cursor.position.read = s.len() as u64;
+            }
+            Some(s) if s.len() < cursor.position.read as usize => {
+                cursor.position.read = s.len() as u64;
+            }
+            _ => {}
+        },
+        None => {
+            cursor.position.read = 0;
+        }
+    }
+
+    // an unused cursor is left unsaved, so it keeps holding no segment
+    if cursor.corrupted || (cursor.saved && cursor.position != start_pos) {
+        cursor.save()?;
+    }
+
+    Ok(())
 }
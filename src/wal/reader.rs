@@ -0,0 +1,147 @@
+use crc::crc32::{Digest, Hasher32, IEEE};
+use segment::Segment;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+
+// bytes read at once when the crc32 has to catch up with skipped data
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// EntryReader streams a single entry out of its segment file, without loading it whole.
+///
+/// The crc32 of the entry is computed along the way if `Config::check_crc32` is set,
+/// and a mismatch is returned as an error once the end of the entry is reached.
+pub struct EntryReader<'a> {
+    segment: &'a Segment,
+    lsn: u64,
+
+    offset: u64,
+    size: u64,
+    crc32: u32,
+    pos: u64,
+
+    check: bool,
+    digest: Digest,
+    // bytes from the beginning of the entry which are already digested
+    digested: u64,
+    verified: bool,
+}
+
+impl<'a> EntryReader<'a> {
+    pub(crate) fn new(segment: &'a Segment, index: usize, check: bool) -> Result<EntryReader<'a>> {
+        let (offset, size, crc32) = segment.entry_span(index)?;
+
+        Ok(EntryReader {
+            segment,
+            lsn: segment.first_lsn() + index as u64,
+            offset,
+            size,
+            crc32,
+            pos: 0,
+            check,
+            digest: Digest::new(IEEE),
+            digested: 0,
+            verified: false,
+        })
+    }
+
+    /// Returns the lsn of the entry.
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    /// Returns the size of the entry.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns true if the entry is empty.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // Feeds the bytes read at pos into the digest, as long as they follow the digested ones.
+    fn digest(&mut self, pos: u64, buf: &[u8]) {
+        let end = pos + buf.len() as u64;
+        if pos <= self.digested && self.digested < end {
+            self.digest.write(&buf[(self.digested - pos) as usize..]);
+            self.digested = end;
+        }
+    }
+
+    // Digests the bytes skipped by seeking, and compares the crc32 with the overhead.
+    fn verify(&mut self) -> Result<()> {
+        let mut chunk = vec![0; CHUNK_SIZE.min((self.size - self.digested) as usize)];
+        while self.digested < self.size {
+            let n = chunk.len().min((self.size - self.digested) as usize);
+            let offset = self.offset + self.digested;
+            let read = self.segment.read_at(&mut chunk[..n], offset)?;
+            if read == 0 {
+                return Err(Error::from(ErrorKind::UnexpectedEof));
+            }
+
+            let pos = self.digested;
+            self.digest(pos, &chunk[..read]);
+        }
+
+        if self.digest.sum32() != self.crc32 {
+            return Err(Error::new(ErrorKind::InvalidData, "fail to check crc32"));
+        }
+
+        self.verified = true;
+        Ok(())
+    }
+}
+
+impl<'a> Read for EntryReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.pos >= self.size {
+            if self.check && !self.verified {
+                self.verify()?;
+            }
+            return Ok(0);
+        }
+
+        let n = buf.len().min((self.size - self.pos) as usize);
+        let read = self
+            .segment
+            .read_at(&mut buf[..n], self.offset + self.pos)?;
+        if read == 0 {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+
+        if self.check {
+            let pos = self.pos;
+            self.digest(pos, &buf[..read]);
+        }
+
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<'a> Seek for EntryReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.size, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+
+        match base.checked_add_signed(delta) {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
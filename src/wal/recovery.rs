@@ -0,0 +1,23 @@
+/// RecoveryReport describes what was discarded from the tail of the wal while opening it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// sequence of the last segment once the tail is recovered, if any
+    pub segment: Option<u64>,
+
+    /// number of torn or corrupted entries discarded, including the ones of an
+    /// atomic batch which was not written completely
+    pub entries_discarded: usize,
+
+    /// number of bytes truncated from the end of the wal
+    pub bytes_truncated: u64,
+
+    /// number of segment files removed as a crash interrupted their creation
+    pub segments_removed: usize,
+}
+
+impl RecoveryReport {
+    /// Returns true if nothing was discarded.
+    pub fn is_clean(&self) -> bool {
+        self.entries_discarded == 0 && self.bytes_truncated == 0 && self.segments_removed == 0
+    }
+}
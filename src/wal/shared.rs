@@ -0,0 +1,156 @@
+use super::{EntryTooLarge, WAL};
+use config::Config;
+use std::ffi::OsStr;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// SharedWAL is a cloneable handle of a wal, which can be used from multiple threads.
+///
+/// Concurrent writes are group committed: the entries queued by all waiting writers
+/// are written and fsynced together by one of them, and every writer returns once
+/// its own entry is durable.
+#[derive(Clone)]
+pub struct SharedWAL {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    wal: Mutex<WAL>,
+    max_entry_size: usize,
+    queue: Mutex<Queue>,
+    cond: Condvar,
+}
+
+struct Queue {
+    leading: bool,
+    pending: Vec<Vec<u8>>,
+    group: Arc<Group>,
+}
+
+#[derive(Default)]
+struct Group {
+    status: Mutex<Status>,
+}
+
+#[derive(Default)]
+enum Status {
+    #[default]
+    Pending,
+    Done(u64),
+    Failed(ErrorKind, String),
+}
+
+impl SharedWAL {
+    /// Opens a wal with given dir and wraps it into a shared handle.
+    pub fn open<S: AsRef<OsStr> + ?Sized>(dir: &S, cfg: Config) -> Result<SharedWAL> {
+        WAL::open(dir, cfg).map(SharedWAL::new)
+    }
+
+    /// Wraps an opened wal.
+    pub fn new(wal: WAL) -> SharedWAL {
+        SharedWAL {
+            inner: Arc::new(Inner {
+                max_entry_size: wal.cfg.max_entry_size,
+                wal: Mutex::new(wal),
+                queue: Mutex::new(Queue {
+                    leading: false,
+                    pending: Vec::new(),
+                    group: Arc::new(Group::default()),
+                }),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Locks the underlying wal for any other operation.
+    pub fn lock(&self) -> MutexGuard<'_, WAL> {
+        self.inner.wal.lock().unwrap()
+    }
+
+    /// Writes bytes to wal, returns the lsn of the entry after it is flushed to disk.
+    pub fn write(&self, data: &[u8]) -> Result<u64> {
+        // rejected before joining a group, which would fail as a whole
+        EntryTooLarge::check(data.len(), self.inner.max_entry_size)?;
+
+        let mut queue = self.inner.queue.lock().unwrap();
+        let index = queue.pending.len() as u64;
+        queue.pending.push(data.to_vec());
+        let group = queue.group.clone();
+
+        loop {
+            match *group.status.lock().unwrap() {
+                Status::Pending => {}
+                Status::Done(first) => return Ok(first + index),
+                Status::Failed(kind, ref msg) => return Err(Error::new(kind, msg.clone())),
+            }
+
+            if queue.leading {
+                queue = self.inner.cond.wait(queue).unwrap();
+                continue;
+            }
+
+            // become the leader and commit the current group
+            queue.leading = true;
+            let entries = mem::take(&mut queue.pending);
+            let leading = mem::take(&mut queue.group);
+            drop(queue);
+
+            let res = self.commit(&entries);
+            *leading.status.lock().unwrap() = match res {
+                Ok(first) => Status::Done(first),
+                Err(ref e) => Status::Failed(e.kind(), e.to_string()),
+            };
+
+            queue = self.inner.queue.lock().unwrap();
+            queue.leading = false;
+            self.inner.cond.notify_all();
+        }
+    }
+
+    // Number of entries queued for the next group.
+    #[cfg(test)]
+    pub(crate) fn queued(&self) -> usize {
+        self.inner.queue.lock().unwrap().pending.len()
+    }
+
+    /// Reads N entries from wal, waits for new ones to be written if there is none.
+    ///
+    /// Returns as soon as at least one entry is read, or an empty result once the timeout expires.
+    pub fn read_timeout(&self, n: usize, timeout: Duration) -> Result<Vec<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let (signal, seen) = {
+                let mut wal = self.lock();
+                let result = wal.read(n)?;
+                if !result.is_empty() || n == 0 {
+                    return Ok(result);
+                }
+
+                // writers notify while holding the lock, so no notification is missed
+                (wal.signal.clone(), wal.signal.generation())
+            };
+
+            if !signal.wait(seen, deadline) {
+                return Ok(Vec::new());
+            }
+        }
+    }
+
+    // Writes and syncs the entries, returns the lsn of the first one.
+    fn commit(&self, entries: &[Vec<u8>]) -> Result<u64> {
+        let batch: Vec<&[u8]> = entries.iter().map(|e| &e[..]).collect();
+
+        let mut wal = self.lock();
+        // a failed group is removed as a whole, so the writers can retry it safely
+        let lsns = wal.rollback_on_error(|wal| {
+            let lsns = wal.append(&batch, true)?;
+            wal.sync()?;
+            Ok(lsns)
+        })?;
+        wal.signal.notify();
+        Ok(lsns.start)
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+/// Signal wakes up the readers waiting for new entries.
+#[derive(Default)]
+pub struct Signal {
+    generation: Mutex<u64>,
+    cond: Condvar,
+}
+
+impl Signal {
+    /// Tells the waiting readers that new entries are written.
+    pub fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.cond.notify_all();
+    }
+
+    /// Returns a counter of the notifications so far.
+    pub fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// Waits until notified after the given generation, or the deadline passes.
+    ///
+    /// Returns false on timeout.
+    pub fn wait(&self, seen: u64, deadline: Instant) -> bool {
+        let mut generation = self.generation.lock().unwrap();
+        while *generation == seen {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            generation = self
+                .cond
+                .wait_timeout(generation, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        true
+    }
+}
@@ -1,11 +1,18 @@
-use config::Config;
+use super::cursor::Cursor;
+use config::{Config, SyncPolicy};
+use fileext;
 use mock::{random_bytes, Home};
 use rand::{thread_rng, Rng};
-use segment::Segment;
+use segment::tests::write_legacy;
+use segment::{Segment, BATCH};
 use std::collections::HashSet;
 use std::fmt::Display;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use wal::WAL;
+use std::thread;
+use std::time::{Duration, Instant};
+use wal::{Entry, EntryTooLarge, Position, RecoveryReport, SharedWAL, WAL};
 
 #[test]
 fn test_open_reopen() {
@@ -13,9 +20,10 @@ fn test_open_reopen() {
     let cfg = Config {
         entry_per_segment: per,
         check_crc32: false,
+        ..Default::default()
     };
 
-    let testhome = Home::new("testdir");
+    let testhome = Home::new("testdir_wal_open_reopen");
     let entry_num: usize = 256;
     let dir = testhome.dir();
     let data = random_bytes(entry_num);
@@ -23,7 +31,7 @@ fn test_open_reopen() {
 
     {
         let title = "init & write";
-        let mut wal = WAL::open(&testhome.dir(), cfg.clone()).unwrap();
+        let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
 
         for i in 0..buf.len() {
             wal.write(&buf[..i + 1]).unwrap();
@@ -31,12 +39,12 @@ fn test_open_reopen() {
 
         assert_eq!(wal.segments.len(), 3, "{}", title);
         assert_eq!(wal.len(), entry_num, "{}", title);
-        assert_segment_exists(&dir, &vec![0, 1, 2], title);
+        assert_segment_exists(&dir, &[0, 1, 2], title);
     }
 
     {
         let title = "read half in segment 0";
-        let mut wal = WAL::open(&testhome.dir(), cfg.clone()).unwrap();
+        let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
 
         let read_n = per / 2;
         let out = wal.read(read_n).unwrap();
@@ -45,12 +53,12 @@ fn test_open_reopen() {
 
         assert_eq!(wal.segments.len(), 3, "{}", title);
         assert_eq!(wal.len(), entry_num - read_n, "{}", title);
-        assert_segment_exists(&dir, &vec![0, 1, 2], title);
+        assert_segment_exists(&dir, &[0, 1, 2], title);
     }
 
     {
         let title = "read whole segment 0";
-        let mut wal = WAL::open(&testhome.dir(), cfg.clone()).unwrap();
+        let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
 
         let read_n = per / 2;
         let out = wal.read(read_n).unwrap();
@@ -59,12 +67,12 @@ fn test_open_reopen() {
 
         assert_eq!(wal.segments.len(), 3, "{}", title);
         assert_eq!(wal.len(), entry_num - per, "{}", title);
-        assert_segment_exists(&dir, &vec![0, 1, 2], title);
+        assert_segment_exists(&dir, &[0, 1, 2], title);
     }
 
     {
         let title = "read half in segment 1";
-        let mut wal = WAL::open(&testhome.dir(), cfg.clone()).unwrap();
+        let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
 
         let read_n = per / 2;
         let out = wal.read(read_n).unwrap();
@@ -73,12 +81,12 @@ fn test_open_reopen() {
 
         assert_eq!(wal.segments.len(), 2, "{}", title);
         assert_eq!(wal.len(), entry_num - per - read_n, "{}", title);
-        assert_segment_exists(&dir, &vec![1, 2], title);
+        assert_segment_exists(&dir, &[1, 2], title);
     }
 
     {
         let title = "read all";
-        let mut wal = WAL::open(&testhome.dir(), cfg.clone()).unwrap();
+        let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
 
         let read_n = entry_num - per / 2 * 3;
         let out = wal.read(entry_num * 2).unwrap();
@@ -87,7 +95,7 @@ fn test_open_reopen() {
 
         assert_eq!(wal.segments.len(), 1, "{}", title);
         assert_eq!(wal.len(), 0, "{}", title);
-        assert_segment_exists(&dir, &vec![2], title);
+        assert_segment_exists(&dir, &[2], title);
     }
 }
 
@@ -105,15 +113,16 @@ fn test_batch() {
     let cfg = Config {
         entry_per_segment: per,
         check_crc32: false,
+        ..Default::default()
     };
 
-    let testhome = Home::new("testdir");
+    let testhome = Home::new("testdir_wal_batch");
     let entry_num: usize = 1024;
-    let segment_num = (entry_num + per - 1) / per;
+    let segment_num = entry_num.div_ceil(per);
     let dir = testhome.dir();
     let data = random_bytes(entry_num);
     let buf = data.as_slice();
-    let mut wal = WAL::open(&dir, cfg.clone()).unwrap();
+    let mut wal = WAL::open(&dir, cfg).unwrap();
 
     {
         let title = "batch write";
@@ -159,3 +168,1835 @@ fn test_batch() {
         assert_eq!(set.len(), entry_num);
     }
 }
+
+#[test]
+fn test_atomic_batch() {
+    let per: usize = 4;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_atomic_batch");
+    let dir = testhome.dir();
+    let data = random_bytes(20);
+    let buf = data.as_slice();
+    let batch: Vec<&[u8]> = (0..6).map(|i| &buf[..i + 1]).collect();
+    let mut wal = WAL::open(&dir, cfg).unwrap();
+
+    {
+        let title = "ended batch";
+        wal.write(&buf[..10]).unwrap();
+        assert_eq!(wal.atomic_batch_write(&batch).unwrap(), 1..7, "{}", title);
+
+        drop(wal);
+        let (mut wal, report) = WAL::open_with_report(&dir, cfg).unwrap();
+        assert!(report.is_clean(), "{}", title);
+        assert_eq!(wal.len(), 7, "{}", title);
+        assert_eq!(&wal.read(2).unwrap()[1][..], batch[0], "{}", title);
+    }
+
+    {
+        let title = "unended batch";
+        // a crash after the first entries of a batch over two segments
+        let mut last = Segment::open(&dir, 1, per, 4, false).unwrap();
+        assert_eq!(
+            last.batch_write(&batch[..1], BATCH, BATCH).unwrap(),
+            1,
+            "{}",
+            title
+        );
+        let mut next = Segment::open(&dir, 2, per, 8, true).unwrap();
+        next.batch_write(&batch[1..3], BATCH, BATCH).unwrap();
+        drop((last, next));
+        assert_segment_exists(&dir, &[0, 1, 2], title);
+
+        let (mut wal, report) = WAL::open_with_report(&dir, cfg).unwrap();
+        assert_eq!(report.entries_discarded, 3, "{}", title);
+        assert_eq!(report.bytes_truncated, 1 + 2 + 3, "{}", title);
+        assert_eq!(report.segment, Some(1), "{}", title);
+        assert!(!dir.join(Segment::filename(2)).exists(), "{}", title);
+
+        assert_eq!(wal.len(), 5, "{}", title);
+        assert_eq!(wal.write(&buf[..12]).unwrap(), 7, "{}", title);
+        let prev = wal.iter_rev().nth(1).unwrap().unwrap();
+        assert_eq!(&prev.data[..], batch[5], "{}", title);
+    }
+
+    {
+        let title = "broken by a failed rollback";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        wal.broken = true;
+        assert!(wal.write(&buf[..1]).is_err(), "{}", title);
+        assert!(wal.batch_write(&batch).is_err(), "{}", title);
+        assert!(wal.atomic_batch_write(&batch).is_err(), "{}", title);
+        assert!(wal.entry_writer().is_err(), "{}", title);
+        assert_eq!(wal.next_lsn(), 8, "{}", title);
+
+        drop(wal);
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.write(&buf[..1]).unwrap(), 8, "{}", title);
+    }
+}
+
+#[test]
+fn test_max_segment_bytes() {
+    let cfg = Config {
+        entry_per_segment: 10,
+        max_segment_bytes: 100,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_max_segment_bytes");
+    let dir = testhome.dir();
+    let data = random_bytes(250);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&dir, cfg).unwrap();
+
+    {
+        let title = "roll by bytes";
+        for _ in 0..4 {
+            wal.write(&buf[..30]).unwrap();
+        }
+        assert_segment_exists(&dir, &[0, 1], title);
+        assert_eq!(wal.segments[0].len(), 3, "{}", title);
+        assert_eq!(wal.segments[0].data_size(), 90, "{}", title);
+    }
+
+    {
+        let title = "oversized entry";
+        assert_eq!(wal.write(buf).unwrap(), 4, "{}", title);
+        assert_eq!(wal.write(&buf[..1]).unwrap(), 5, "{}", title);
+        assert_segment_exists(&dir, &[0, 1, 2, 3], title);
+        assert_eq!(wal.segments[2].len(), 1, "{}", title);
+    }
+
+    {
+        let title = "batch over segments";
+        let batch: Vec<&[u8]> = vec![&buf[..40]; 7];
+        assert_eq!(wal.batch_write(&batch).unwrap(), 6..13, "{}", title);
+        let lens: Vec<usize> = wal.segments.iter().map(|s| s.len()).collect();
+        assert_eq!(lens, vec![3, 1, 1, 3, 2, 2, 1], "{}", title);
+
+        let out = wal.read(13).unwrap();
+        assert_eq!(out.len(), 13, "{}", title);
+        assert_eq!(&out[4][..], buf, "{}", title);
+        assert!(out[6..].iter().all(|e| e[..] == buf[..40]), "{}", title);
+    }
+}
+
+#[test]
+fn test_max_segment_age() {
+    let cfg = Config {
+        entry_per_segment: 10,
+        max_segment_age: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_max_segment_age");
+    let dir = testhome.dir();
+    let mut wal = WAL::open(&dir, cfg).unwrap();
+
+    {
+        let title = "young segment";
+        wal.write(b"first").unwrap();
+        wal.write(b"second").unwrap();
+        assert_segment_exists(&dir, &[0], title);
+    }
+
+    {
+        let title = "expired segment";
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(wal.write(b"third").unwrap(), 2, "{}", title);
+        assert_segment_exists(&dir, &[0, 1], title);
+        assert_eq!(wal.segments[0].len(), 2, "{}", title);
+    }
+
+    {
+        let title = "creation time kept on reopen";
+        drop(wal);
+        thread::sleep(Duration::from_millis(150));
+
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert!(
+            wal.segments[1].age() >= Duration::from_millis(150),
+            "{}",
+            title
+        );
+        wal.write(b"fourth").unwrap();
+        assert_segment_exists(&dir, &[0, 1, 2], title);
+        assert_eq!(wal.read(10).unwrap().len(), 4, "{}", title);
+    }
+}
+
+#[test]
+fn test_max_entry_size() {
+    let cfg = Config {
+        entry_per_segment: 10,
+        max_entry_size: 64,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_max_entry_size");
+    let dir = testhome.dir();
+    let data = random_bytes(100);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&dir, cfg).unwrap();
+
+    let too_large = |e: Error| {
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        *e.get_ref()
+            .unwrap()
+            .downcast_ref::<EntryTooLarge>()
+            .unwrap()
+    };
+
+    {
+        let title = "oversized write";
+        assert_eq!(wal.write(&buf[..64]).unwrap(), 0, "{}", title);
+        let err = too_large(wal.write(&buf[..65]).unwrap_err());
+        assert_eq!(
+            err,
+            EntryTooLarge {
+                size: 65,
+                limit: 64
+            },
+            "{}",
+            title
+        );
+
+        let batch: Vec<&[u8]> = vec![&buf[..10], &buf[..70]];
+        too_large(wal.batch_write(&batch).unwrap_err());
+        too_large(wal.atomic_batch_write(&batch).unwrap_err());
+
+        let mut writer = wal.entry_writer().unwrap();
+        writer.write_all(&buf[..60]).unwrap();
+        too_large(writer.write_all(&buf[..10]).unwrap_err());
+        drop(writer);
+
+        assert_eq!(wal.len(), 1, "{}", title);
+    }
+
+    {
+        let title = "oversized entry on disk";
+        drop(wal);
+        let big = Config {
+            max_entry_size: 0,
+            ..cfg
+        };
+        let mut wal = WAL::open(&dir, big).unwrap();
+        wal.write(buf).unwrap();
+        wal.write(&buf[..1]).unwrap();
+        drop(wal);
+
+        let (mut wal, report) = WAL::open_with_report(&dir, cfg).unwrap();
+        assert!(report.is_clean(), "{}", title);
+        assert_eq!(wal.len(), 3, "{}", title);
+        assert_eq!(wal.read(1).unwrap()[0], &buf[..64], "{}", title);
+
+        let err = wal.read(1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", title);
+        assert!(
+            wal.get(1)
+                .unwrap_err()
+                .to_string()
+                .contains("entry too large"),
+            "{}",
+            title
+        );
+        assert_eq!(wal.get(2).unwrap().unwrap(), &buf[..1], "{}", title);
+    }
+
+    {
+        let title = "shared wal";
+        let shared = SharedWAL::open(&testhome.dir(), cfg).unwrap();
+        too_large(shared.write(&buf[..65]).unwrap_err());
+        assert_eq!(shared.write(&buf[..2]).unwrap(), 3, "{}", title);
+    }
+
+    {
+        let title = "truncate past an oversized entry";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        wal.truncate_after(0).unwrap();
+        assert_eq!(wal.last_lsn(), Some(0), "{}", title);
+        assert_eq!(wal.write(&buf[..3]).unwrap(), 1, "{}", title);
+    }
+}
+
+#[test]
+fn test_sync_policy() {
+    let testhome = Home::new("testdir_wal_sync_policy");
+    let dir = testhome.dir();
+    let data = random_bytes(32);
+
+    {
+        let title = "sync every 3 entries";
+        let cfg = Config {
+            entry_per_segment: 10,
+            sync: SyncPolicy::Entries(3),
+            ..Default::default()
+        };
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        let opened = wal.last_sync;
+
+        wal.write(&data).unwrap();
+        wal.write(&data).unwrap();
+        assert_eq!(wal.unsynced, 2, "{}", title);
+        assert_eq!(wal.last_sync, opened, "{}", title);
+
+        wal.write(&data).unwrap();
+        assert_eq!(wal.unsynced, 0, "{}", title);
+        assert!(wal.last_sync > opened, "{}", title);
+
+        wal.batch_write(&[&data[..], &data[..]]).unwrap();
+        assert_eq!(wal.unsynced, 2, "{}", title);
+
+        wal.sync().unwrap();
+        assert_eq!(wal.unsynced, 0, "{}", title);
+    }
+
+    {
+        let title = "sync always";
+        let cfg = Config {
+            entry_per_segment: 10,
+            sync: SyncPolicy::Always,
+            ..Default::default()
+        };
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        let opened = wal.last_sync;
+
+        wal.batch_write(&[&data[..], &data[..], &data[..]]).unwrap();
+        assert_eq!(wal.unsynced, 0, "{}", title);
+        assert!(wal.last_sync > opened, "{}", title);
+        assert_eq!(wal.len(), 8, "{}", title);
+    }
+
+    {
+        let title = "sync once the interval passed";
+        let interval = Duration::from_millis(50);
+        let cfg = Config {
+            entry_per_segment: 10,
+            sync: SyncPolicy::Interval(interval),
+            ..Default::default()
+        };
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        let opened = wal.last_sync;
+
+        wal.write(&data).unwrap();
+        wal.batch_write(&[&data[..], &data[..]]).unwrap();
+        assert_eq!(wal.unsynced, 3, "{}", title);
+        assert_eq!(wal.last_sync, opened, "{}", title);
+
+        thread::sleep(interval);
+        wal.write(&data).unwrap();
+        assert_eq!(wal.unsynced, 0, "{}", title);
+        assert!(wal.last_sync > opened, "{}", title);
+
+        let synced = wal.last_sync;
+        wal.write(&data).unwrap();
+        assert_eq!(wal.unsynced, 1, "{}", title);
+        assert_eq!(wal.last_sync, synced, "{}", title);
+
+        // the tail of a burst is synced by the caller once it is due
+        assert!(!wal.sync_if_due().unwrap(), "{}", title);
+        assert_eq!(wal.unsynced, 1, "{}", title);
+        thread::sleep(interval);
+        assert!(wal.sync_if_due().unwrap(), "{}", title);
+        assert_eq!(wal.unsynced, 0, "{}", title);
+        assert!(wal.last_sync > synced, "{}", title);
+        thread::sleep(interval);
+        assert!(!wal.sync_if_due().unwrap(), "{}", title);
+    }
+
+    {
+        let title = "sync never";
+        let cfg = Config {
+            entry_per_segment: 10,
+            sync: SyncPolicy::Never,
+            ..Default::default()
+        };
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        let opened = wal.last_sync;
+
+        for _ in 0..12 {
+            wal.write(&data).unwrap();
+        }
+        assert_eq!(wal.unsynced, 12, "{}", title);
+        assert_eq!(wal.last_sync, opened, "{}", title);
+
+        wal.sync().unwrap();
+        assert_eq!(wal.unsynced, 0, "{}", title);
+        assert!(wal.last_sync > opened, "{}", title);
+    }
+}
+
+#[test]
+fn test_group_commit() {
+    let testhome = Home::new("testdir_wal_group_commit");
+    let cfg = Config {
+        entry_per_segment: 64,
+        ..Default::default()
+    };
+
+    let threads: usize = 8;
+    let per_thread: usize = 50;
+    let shared = SharedWAL::open(&testhome.dir(), cfg).unwrap();
+
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for i in 0..per_thread {
+                    shared.write(&[t as u8, i as u8]).unwrap();
+                }
+            })
+        })
+        .collect();
+
+    for h in handles {
+        h.join().unwrap();
+    }
+
+    let mut wal = shared.lock();
+    assert_eq!(wal.unsynced, 0);
+    assert_eq!(wal.len(), threads * per_thread);
+    assert_eq!(wal.last_lsn(), Some((threads * per_thread - 1) as u64));
+
+    let mut set = HashSet::new();
+    for one in wal.read(threads * per_thread).unwrap() {
+        assert!(set.insert(one));
+    }
+    assert_eq!(set.len(), threads * per_thread);
+    drop(wal);
+
+    // while the wal is locked, the first writer leads a group of its own and waits
+    // for the lock, all the others queue up behind it and are committed together
+    let guard = shared.lock();
+    let syncs = guard.syncs;
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let shared = shared.clone();
+            thread::spawn(move || shared.write(&[t as u8]).unwrap())
+        })
+        .collect();
+
+    while shared.queued() < threads - 1 {
+        thread::yield_now();
+    }
+    drop(guard);
+
+    for h in handles {
+        h.join().unwrap();
+    }
+
+    let wal = shared.lock();
+    assert_eq!(wal.unsynced, 0);
+    assert_eq!(wal.len(), threads);
+    assert_eq!(wal.syncs - syncs, 2);
+}
+
+#[test]
+fn test_cursor_recovery() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_cursor_recovery");
+    let dir = testhome.dir();
+    let cursor_file = dir.join("cursor");
+    let data = random_bytes(32);
+
+    {
+        let title = "write & read";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+
+        for _ in 0..3 * per {
+            wal.write(&data).unwrap();
+        }
+
+        assert_eq!(wal.read(per + 5).unwrap().len(), per + 5, "{}", title);
+        assert_eq!(wal.len(), 2 * per - 5, "{}", title);
+        assert!(!dir.join("tmp.cursor").exists(), "{}", title);
+        assert_eq!(fs::metadata(&cursor_file).unwrap().len(), 36, "{}", title);
+    }
+
+    {
+        let title = "legacy cursor";
+        let contents = fs::read(&cursor_file).unwrap();
+        fs::write(&cursor_file, &contents[..32]).unwrap();
+
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.len(), 2 * per - 5, "{}", title);
+    }
+
+    {
+        let title = "truncated cursor";
+        let contents = fs::read(&cursor_file).unwrap();
+        fs::write(&cursor_file, &contents[..10]).unwrap();
+
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.len(), 2 * per, "{}", title);
+        assert_eq!(fs::metadata(&cursor_file).unwrap().len(), 36, "{}", title);
+    }
+
+    {
+        let title = "damaged cursor";
+        let mut contents = fs::read(&cursor_file).unwrap();
+        contents[20] ^= 0xff;
+        fs::write(&cursor_file, &contents).unwrap();
+
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.len(), 2 * per, "{}", title);
+    }
+}
+
+#[test]
+fn test_recovery_report() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_recovery_report");
+    let dir = testhome.dir();
+    let data = random_bytes(32);
+
+    {
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        for _ in 0..per + 5 {
+            wal.write(&data).unwrap();
+        }
+    }
+
+    {
+        let title = "torn write";
+        let fname = dir.join(Segment::filename(1));
+        let len = fs::metadata(&fname).unwrap().len();
+        let f = fs::OpenOptions::new().write(true).open(&fname).unwrap();
+        f.set_len(len - 8).unwrap();
+
+        let (wal, report) = WAL::open_with_report(&dir, cfg).unwrap();
+        assert_eq!(
+            report,
+            RecoveryReport {
+                segment: Some(1),
+                entries_discarded: 1,
+                bytes_truncated: 24,
+                segments_removed: 0,
+            },
+            "{}",
+            title
+        );
+        assert!(!report.is_clean(), "{}", title);
+        assert_eq!(wal.len(), per + 4, "{}", title);
+    }
+
+    {
+        let title = "clean reopen";
+        let (wal, report) = WAL::open_with_report(&dir, cfg).unwrap();
+        assert!(report.is_clean(), "{}", title);
+        assert_eq!(wal.len(), per + 4, "{}", title);
+    }
+
+    for (title, size) in [
+        ("zero filled segment", 64 + per * 32),
+        ("torn segment head", 10),
+    ] {
+        // a crash after segment 2 is created, before its head is on disk
+        let fname = dir.join(Segment::filename(2));
+        fs::write(&fname, vec![0; size]).unwrap();
+
+        let (mut wal, report) = WAL::open_with_report(&dir, cfg).unwrap();
+        assert_eq!(report.segments_removed, 1, "{}", title);
+        assert_eq!(report.segment, Some(1), "{}", title);
+        assert!(!report.is_clean(), "{}", title);
+        assert!(!fname.exists(), "{}", title);
+        assert_eq!(wal.last_lsn(), Some(per as u64 + 3), "{}", title);
+
+        assert_eq!(wal.write(&data).unwrap(), per as u64 + 4, "{}", title);
+        wal.truncate_after(per as u64 + 3).unwrap();
+    }
+}
+
+#[test]
+fn test_iter() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_iter");
+    let entry_num: usize = 45;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
+
+    for i in 0..entry_num {
+        wal.write(&buf[..i + 1]).unwrap();
+    }
+
+    {
+        let title = "iter all";
+        let all: Vec<Entry> = wal.iter().map(|e| e.unwrap()).collect();
+        assert_eq!(all.len(), entry_num, "{}", title);
+        for (i, v) in all.iter().enumerate() {
+            assert_eq!(i as u64, v.lsn, "{}", title);
+            assert_eq!(&buf[..i + 1], &v.data[..], "{}", title);
+        }
+        assert_eq!(wal.len(), entry_num, "{}", title);
+    }
+
+    {
+        let title = "iter unread";
+        wal.read(per + 3).unwrap();
+
+        let mut iter = wal.iter();
+        assert_eq!(
+            iter.position(),
+            Position {
+                sequence: 1,
+                read: 3
+            },
+            "{}",
+            title
+        );
+        let next = iter.next().unwrap().unwrap();
+        assert_eq!(next.lsn, (per + 3) as u64, "{}", title);
+        assert_eq!(next.data, &buf[..per + 4], "{}", title);
+        assert_eq!(
+            iter.position(),
+            Position {
+                sequence: 1,
+                read: 4
+            },
+            "{}",
+            title
+        );
+        assert_eq!(iter.count(), entry_num - per - 4, "{}", title);
+        assert_eq!(wal.len(), entry_num - per - 3, "{}", title);
+        assert_segment_exists(&testhome.dir(), &[1, 2, 3, 4], title);
+    }
+
+    {
+        let title = "iter from";
+        let pos = Position {
+            sequence: 1,
+            read: 0,
+        };
+        let from: Vec<Entry> = wal.iter_from(pos).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(from.len(), entry_num - per, "{}", title);
+        assert_eq!(from[0].lsn, per as u64, "{}", title);
+        assert_eq!(&from[0].data[..], &buf[..per + 1], "{}", title);
+
+        let pos = Position {
+            sequence: 4,
+            read: 5,
+        };
+        assert_eq!(wal.iter_from(pos).unwrap().count(), 0, "{}", title);
+
+        let pos = Position {
+            sequence: 0,
+            read: 0,
+        };
+        assert!(wal.iter_from(pos).is_err(), "{}", title);
+
+        let pos = Position {
+            sequence: 4,
+            read: 6,
+        };
+        assert!(wal.iter_from(pos).is_err(), "{}", title);
+    }
+}
+
+#[test]
+fn test_iter_rev() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_iter_rev");
+    let entry_num: usize = 25;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
+
+    let title = "empty wal";
+    assert!(wal.iter_rev().next().is_none(), "{}", title);
+
+    for i in 0..entry_num {
+        wal.write(&buf[..i + 1]).unwrap();
+    }
+
+    {
+        let title = "latest entries";
+        let last: Vec<Entry> = wal.iter_rev().take(3).map(|e| e.unwrap()).collect();
+        assert_eq!(last.len(), 3, "{}", title);
+        assert_eq!(last[0].lsn, (entry_num - 1) as u64, "{}", title);
+        assert_eq!(&last[0].data[..], buf, "{}", title);
+        assert_eq!(last[2].lsn, (entry_num - 3) as u64, "{}", title);
+        assert_eq!(wal.len(), entry_num, "{}", title);
+    }
+
+    {
+        let title = "all entries on disk";
+        wal.read(per + 1).unwrap();
+
+        let all: Vec<Entry> = wal.iter_rev().map(|e| e.unwrap()).collect();
+        assert_eq!(all.len(), entry_num - per, "{}", title);
+        for (i, e) in all.iter().rev().enumerate() {
+            assert_eq!(e.lsn, (per + i) as u64, "{}", title);
+            assert_eq!(&e.data[..], &buf[..per + i + 1], "{}", title);
+        }
+    }
+
+    {
+        let title = "across an empty last segment";
+        for i in 0..per - 5 {
+            wal.write(&buf[..i + 1]).unwrap();
+        }
+        assert_eq!(wal.segments.len(), 2, "{}", title);
+
+        // like the one allocated right before a failed write
+        let next = wal.next_lsn();
+        wal.segments
+            .push(Segment::open(&testhome.dir(), 3, per, next, true).unwrap());
+
+        let first = wal.iter_rev().next().unwrap().unwrap();
+        assert_eq!(first.lsn, 29, "{}", title);
+        assert_eq!(&first.data[..], &buf[..per - 5], "{}", title);
+    }
+}
+
+#[test]
+fn test_peek() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_peek");
+    let entry_num: usize = 25;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
+
+    for i in 0..entry_num {
+        wal.write(&buf[..i + 1]).unwrap();
+    }
+
+    wal.read(per - 2).unwrap();
+
+    let title = "peek across segments";
+    let peeked = wal.peek(per).unwrap();
+    assert_eq!(peeked.len(), per, "{}", title);
+    assert_eq!(wal.len(), entry_num - per + 2, "{}", title);
+    assert_segment_exists(&testhome.dir(), &[0, 1, 2], title);
+
+    let read = wal.read(per).unwrap();
+    assert_eq!(peeked, read, "{}", title);
+    assert_eq!(&read[0][..], &buf[..per - 1], "{}", title);
+    assert_eq!(wal.segments.len(), 2, "{}", title);
+
+    let title = "peek beyond the end";
+    let peeked = wal.peek(entry_num).unwrap();
+    assert_eq!(peeked.len(), entry_num - 2 * per + 2, "{}", title);
+    assert_eq!(wal.peek(0).unwrap().len(), 0, "{}", title);
+    assert_eq!(wal.read(entry_num).unwrap(), peeked, "{}", title);
+    assert!(wal.peek(1).unwrap().is_empty(), "{}", title);
+}
+
+#[test]
+fn test_read_bytes() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_read_bytes");
+    let entry_num: usize = 25;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
+
+    for i in 0..entry_num {
+        wal.write(&buf[..i + 1]).unwrap();
+    }
+
+    let title = "within byte budget";
+    let read = wal.read_bytes(entry_num, 10).unwrap();
+    assert_eq!(read.len(), 4, "{}", title);
+    assert_eq!(&read[3][..], &buf[..4], "{}", title);
+
+    let title = "entry larger than budget";
+    let read = wal.read_bytes(entry_num, 4).unwrap();
+    assert_eq!(read.len(), 1, "{}", title);
+    assert_eq!(&read[0][..], &buf[..5], "{}", title);
+
+    let title = "budget across segments";
+    let read = wal.read_bytes(entry_num, 6 + 7 + 8 + 9 + 10 + 11).unwrap();
+    assert_eq!(read.len(), 6, "{}", title);
+    assert_eq!(&read[5][..], &buf[..11], "{}", title);
+
+    let title = "entry limit";
+    assert_eq!(wal.read_bytes(2, usize::MAX).unwrap().len(), 2, "{}", title);
+    assert!(
+        wal.read_bytes(0, usize::MAX).unwrap().is_empty(),
+        "{}",
+        title
+    );
+
+    let title = "read to the end";
+    let read = wal.read_bytes(entry_num, usize::MAX).unwrap();
+    assert_eq!(read.len(), entry_num - 13, "{}", title);
+    assert!(
+        wal.read_bytes(entry_num, 1).unwrap().is_empty(),
+        "{}",
+        title
+    );
+}
+
+#[test]
+fn test_read_with() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_read_with");
+    let entry_num: usize = 25;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
+
+    for i in 0..entry_num {
+        wal.write(&buf[..i + 1]).unwrap();
+    }
+
+    {
+        let title = "read with callback";
+        let mut seen = Vec::new();
+        let read = wal.read_with(per + 2, |e| seen.push(e.to_vec())).unwrap();
+        assert_eq!(read, per + 2, "{}", title);
+        for (i, e) in seen.iter().enumerate() {
+            assert_eq!(&e[..], &buf[..i + 1], "{}", title);
+        }
+        assert_eq!(wal.len(), entry_num - per - 2, "{}", title);
+        assert_segment_exists(&testhome.dir(), &[1, 2], title);
+
+        // the buffers are kept for the next read
+        assert!(wal.scratch.capacity() > 0, "{}", title);
+        assert!(wal.table.capacity() > 0, "{}", title);
+    }
+
+    {
+        let title = "read into buf";
+        let mut out = b"kept".to_vec();
+        let mut ranges = Vec::new();
+        let read = wal.read_into_buf(5, &mut out, &mut ranges).unwrap();
+        assert_eq!(read, 5, "{}", title);
+        assert_eq!(&out[..4], b"kept", "{}", title);
+        assert_eq!(ranges[0].start, 4, "{}", title);
+        for (i, r) in ranges.iter().enumerate() {
+            assert_eq!(&out[r.clone()], &buf[..per + 3 + i], "{}", title);
+        }
+
+        let read = wal.read_into_buf(entry_num, &mut out, &mut ranges).unwrap();
+        assert_eq!(read, entry_num - per - 7, "{}", title);
+        assert_eq!(ranges.len(), entry_num - per - 2, "{}", title);
+        assert_eq!(&out[ranges[ranges.len() - 1].clone()], buf, "{}", title);
+        assert_eq!(
+            wal.read_with(1, |_| panic!("no entry left")).unwrap(),
+            0,
+            "{}",
+            title
+        );
+    }
+}
+
+#[test]
+fn test_read_pending_commit() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_read_pending_commit");
+    let dir = testhome.dir();
+    let entry_num: usize = 25;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+
+    {
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        for i in 0..entry_num {
+            wal.write(&buf[..i + 1]).unwrap();
+        }
+    }
+
+    {
+        let title = "crash before commit";
+        let wal = WAL::open(&dir, cfg).unwrap();
+        let (out, _token) = wal.read_pending(per + 5).unwrap();
+        assert_eq!(out.len(), per + 5, "{}", title);
+        assert_eq!(wal.len(), entry_num, "{}", title);
+    }
+
+    {
+        let title = "commit";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.len(), entry_num, "{}", title);
+
+        let (out, token) = wal.read_pending(per + 5).unwrap();
+        assert_eq!(&out[0][..], &buf[..1], "{}", title);
+        assert_eq!(
+            token.position(),
+            &Position {
+                sequence: 1,
+                read: 5
+            },
+            "{}",
+            title
+        );
+
+        wal.commit(token.clone()).unwrap();
+        assert_eq!(wal.len(), entry_num - per - 5, "{}", title);
+        assert_segment_exists(&dir, &[1, 2], title);
+
+        wal.commit(token).unwrap();
+        assert_eq!(wal.len(), entry_num - per - 5, "{}", title);
+    }
+
+    {
+        let title = "reopen after commit";
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.len(), entry_num - per - 5, "{}", title);
+
+        let (out, _) = wal.read_pending(1).unwrap();
+        assert_eq!(&out[0][..], &buf[..per + 6], "{}", title);
+    }
+}
+
+#[test]
+fn test_lsn() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_lsn");
+    let dir = testhome.dir();
+    let data = random_bytes(16);
+
+    {
+        let title = "empty";
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.first_lsn(), None, "{}", title);
+        assert_eq!(wal.last_lsn(), None, "{}", title);
+    }
+
+    {
+        let title = "write";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        for i in 0..per + 3 {
+            assert_eq!(wal.write(&data).unwrap(), i as u64, "{}", title);
+        }
+
+        let batch: Vec<&[u8]> = vec![&data[..]; per];
+        let lsns = wal.batch_write(&batch).unwrap();
+        assert_eq!(lsns, (per + 3) as u64..(2 * per + 3) as u64, "{}", title);
+        assert_eq!(wal.first_lsn(), Some(0), "{}", title);
+        assert_eq!(wal.last_lsn(), Some((2 * per + 2) as u64), "{}", title);
+    }
+
+    {
+        let title = "read after reopen";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.last_lsn(), Some((2 * per + 2) as u64), "{}", title);
+
+        let out = wal.read_entries(per + 1).unwrap();
+        let lsns: Vec<u64> = out.iter().map(|e| e.lsn).collect();
+        assert_eq!(
+            lsns,
+            (0..(per + 1) as u64).collect::<Vec<u64>>(),
+            "{}",
+            title
+        );
+
+        let out = wal.read_entries(per).unwrap();
+        assert_eq!(out[0].lsn, (per + 1) as u64, "{}", title);
+        assert_eq!(wal.first_lsn(), Some((2 * per) as u64), "{}", title);
+    }
+
+    {
+        let title = "consume everything";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.read(3 * per).unwrap().len(), 2, "{}", title);
+        assert_eq!(wal.read(3 * per).unwrap().len(), 0, "{}", title);
+        assert_eq!(wal.segments.len(), 1, "{}", title);
+        assert_eq!(wal.last_lsn(), Some((2 * per + 2) as u64), "{}", title);
+    }
+
+    {
+        let title = "keep counting after reopen";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.write(&data).unwrap(), (2 * per + 3) as u64, "{}", title);
+        assert_eq!(
+            wal.read_entries(2).unwrap()[0].lsn,
+            (2 * per + 3) as u64,
+            "{}",
+            title
+        );
+    }
+}
+
+#[test]
+fn test_random_access() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_random_access");
+    let entry_num: usize = 35;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
+
+    for i in 0..entry_num {
+        wal.write(&buf[..i + 1]).unwrap();
+    }
+    wal.read(per + 1).unwrap();
+
+    {
+        let title = "get";
+        assert_eq!(wal.get(0).unwrap(), None, "{}", title);
+        assert_eq!(
+            wal.get(per as u64).unwrap().unwrap(),
+            &buf[..per + 1],
+            "{}",
+            title
+        );
+        assert_eq!(wal.get(34).unwrap().unwrap(), buf, "{}", title);
+        assert_eq!(wal.get(35).unwrap(), None, "{}", title);
+        assert_eq!(wal.len(), entry_num - per - 1, "{}", title);
+    }
+
+    {
+        let title = "read range";
+        let out = wal.read_range(15, 32).unwrap();
+        assert_eq!(out.len(), 17, "{}", title);
+        for (i, e) in out.iter().enumerate() {
+            assert_eq!(e.lsn, (15 + i) as u64, "{}", title);
+            assert_eq!(&e.data[..], &buf[..16 + i], "{}", title);
+        }
+
+        assert_eq!(wal.read_range(30, 100).unwrap().len(), 5, "{}", title);
+        assert!(wal.read_range(35, 100).unwrap().is_empty(), "{}", title);
+        assert!(wal.read_range(20, 20).unwrap().is_empty(), "{}", title);
+        assert!(wal.read_range(5, 20).is_err(), "{}", title);
+        assert_eq!(wal.len(), entry_num - per - 1, "{}", title);
+    }
+}
+
+#[test]
+fn test_entry_reader() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_entry_reader");
+    let big: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
+
+    for i in 0..per + 2 {
+        if i == per {
+            wal.write(&big).unwrap();
+        } else {
+            wal.write(&[i as u8; 16]).unwrap();
+        }
+    }
+
+    {
+        let title = "read whole entry";
+        let mut reader = wal.entry_reader(per as u64).unwrap();
+        assert_eq!(reader.lsn(), per as u64, "{}", title);
+        assert_eq!(reader.len(), big.len() as u64, "{}", title);
+
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, big, "{}", title);
+    }
+
+    {
+        let title = "seek within entry";
+        let pos = Position {
+            sequence: 0,
+            read: per as u64,
+        };
+        let mut reader = wal.entry_reader(pos).unwrap();
+        assert_eq!(reader.lsn(), per as u64, "{}", title);
+
+        let mut out = [0; 100];
+        reader.seek(SeekFrom::Start(150_000)).unwrap();
+        reader.read_exact(&mut out).unwrap();
+        assert_eq!(&out[..], &big[150_000..150_100], "{}", title);
+
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(&tail[..], &big[big.len() - 10..], "{}", title);
+        assert!(
+            reader.seek(SeekFrom::Current(-300_000)).is_err(),
+            "{}",
+            title
+        );
+    }
+
+    {
+        let title = "missing entry";
+        assert!(wal.entry_reader(per as u64 + 2).is_err(), "{}", title);
+    }
+
+    {
+        let title = "crc32 mismatch reported at eof";
+        let path = testhome.dir().join(Segment::filename(1));
+        let mut bytes = fs::read(&path).unwrap();
+        let at = bytes.len() - 16 - 1000;
+        bytes[at] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut reader = wal.entry_reader(per as u64).unwrap();
+        let mut head = [0; 100];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(&head[..], &big[..100], "{}", title);
+
+        let mut out = Vec::new();
+        let err = reader.read_to_end(&mut out).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", title);
+        assert_eq!(out.len(), big.len() - 100, "{}", title);
+    }
+}
+
+#[test]
+fn test_entry_writer() {
+    let per: usize = 2;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_entry_writer");
+    let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
+    wal.write(b"first").unwrap();
+
+    {
+        let title = "streamed entry";
+        let mut writer = wal.entry_writer().unwrap();
+        for chunk in &[&b"hello "[..], b"streaming ", b"world"] {
+            writer.write_all(chunk).unwrap();
+        }
+        assert_eq!(writer.len(), 21, "{}", title);
+        assert_eq!(writer.finish().unwrap(), 1, "{}", title);
+    }
+
+    {
+        let title = "aborted entry";
+        let mut writer = wal.entry_writer().unwrap();
+        writer.write_all(&[0xaa; 4096]).unwrap();
+        drop(writer);
+        assert_segment_exists(&testhome.dir(), &[0, 1], title);
+        assert_eq!(wal.next_lsn(), 2, "{}", title);
+
+        assert_eq!(wal.write(b"last").unwrap(), 2, "{}", title);
+        assert_eq!(wal.len(), 3, "{}", title);
+    }
+
+    {
+        let title = "reopen";
+        drop(wal);
+        let (mut wal, report) = WAL::open_with_report(&testhome.dir(), cfg).unwrap();
+        assert!(report.is_clean(), "{}", title);
+
+        let out = wal.read(10).unwrap();
+        assert_eq!(
+            out,
+            vec![
+                b"first".to_vec(),
+                b"hello streaming world".to_vec(),
+                b"last".to_vec()
+            ],
+            "{}",
+            title
+        );
+    }
+}
+
+#[test]
+fn test_truncate_after() {
+    let per: usize = 4;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_truncate_after");
+    let dir = testhome.dir();
+    let data = random_bytes(20);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&dir, cfg).unwrap();
+
+    for i in 0..14 {
+        wal.write(&buf[..i + 1]).unwrap();
+    }
+    wal.read(2).unwrap();
+    wal.open_consumer("follower").unwrap().read(14).unwrap();
+
+    {
+        let title = "truncate by lsn";
+        wal.truncate_after(5).unwrap();
+        assert_segment_exists(&dir, &[0, 1], title);
+        assert!(!dir.join(Segment::filename(2)).exists(), "{}", title);
+        assert_eq!(wal.last_lsn(), Some(5), "{}", title);
+        assert_eq!(wal.len(), 4, "{}", title);
+
+        let consumer = wal.open_consumer("follower").unwrap();
+        assert_eq!(
+            consumer.position(),
+            &Position {
+                sequence: 1,
+                read: 2
+            },
+            "{}",
+            title
+        );
+
+        assert_eq!(wal.write(b"new").unwrap(), 6, "{}", title);
+        assert_segment_exists(&dir, &[0, 1], title);
+    }
+
+    {
+        let title = "truncate by position";
+        let pos = Position {
+            sequence: 1,
+            read: 1,
+        };
+        wal.truncate_after(pos).unwrap();
+        assert_eq!(wal.last_lsn(), Some(5), "{}", title);
+
+        wal.truncate_after(5).unwrap();
+        wal.truncate_after(100).unwrap();
+        assert_eq!(wal.last_lsn(), Some(5), "{}", title);
+    }
+
+    {
+        let title = "truncate at segment end";
+        wal.truncate_after(3).unwrap();
+        assert_eq!(wal.segments.len(), 2, "{}", title);
+        assert_eq!(wal.segments[1].len(), 0, "{}", title);
+        assert_eq!(wal.write(b"again").unwrap(), 4, "{}", title);
+
+        drop(wal);
+        let (mut wal, report) = WAL::open_with_report(&dir, cfg).unwrap();
+        assert!(report.is_clean(), "{}", title);
+        let out: Vec<Entry> = wal.iter_from(0).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(out.len(), 5, "{}", title);
+        assert_eq!(&out[3].data[..], &buf[..4], "{}", title);
+        assert_eq!(&out[4].data[..], b"again", "{}", title);
+
+        wal.truncate_after(0).unwrap();
+        assert_eq!(wal.last_lsn(), Some(0), "{}", title);
+        assert_segment_exists(&dir, &[0], title);
+    }
+
+    {
+        let title = "interrupted truncation";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        for i in 0..8 {
+            wal.write(&buf[..i + 1]).unwrap();
+        }
+        assert_eq!(wal.segments[2].len(), 1, "{}", title);
+        drop(wal);
+
+        // a crash once the marker is saved, and the first removed overhead of segment 1
+        // is cleared
+        let position = Position {
+            sequence: 1,
+            read: 1,
+        };
+        Cursor::truncation(&dir, position).save().unwrap();
+        let path = dir.join(Segment::filename(1));
+        let f = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        fileext::write_all_at(&f, &[0; 32], 64 + 32).unwrap();
+        drop(f);
+
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert!(!dir.join(Segment::filename(2)).exists(), "{}", title);
+        assert!(!dir.join("truncation").exists(), "{}", title);
+        assert_eq!(wal.last_lsn(), Some(4), "{}", title);
+
+        // the stale overheads after the cleared one are gone as well
+        wal.write(b"after").unwrap();
+        drop(wal);
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.last_lsn(), Some(5), "{}", title);
+        assert_eq!(wal.get(5).unwrap().unwrap(), b"after", "{}", title);
+    }
+
+    {
+        let title = "truncation failed before the removals";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        for i in 0..4 {
+            wal.write(&buf[..i + 1]).unwrap();
+        }
+        assert_eq!(wal.segments[2].len(), 2, "{}", title);
+
+        // truncate_after(4) stopped once segment 1 is cut
+        let mut marker = Cursor::truncation(
+            &dir,
+            Position {
+                sequence: 1,
+                read: 1,
+            },
+        );
+        marker.save().unwrap();
+        wal.truncation = Some(marker);
+        wal.segments[1].truncate(1).unwrap();
+        wal.segments.truncate(2);
+        wal.next_sequence = 2;
+        assert_segment_exists(&dir, &[2], title);
+
+        let batch: Vec<&[u8]> = vec![b"a", b"b", b"c", b"d"];
+        assert_eq!(wal.batch_write(&batch).unwrap(), 5..9, "{}", title);
+        assert!(!dir.join("truncation").exists(), "{}", title);
+
+        drop(wal);
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.last_lsn(), Some(8), "{}", title);
+        assert_eq!(wal.get(8).unwrap().unwrap(), b"d", "{}", title);
+    }
+
+    {
+        let title = "crash after the cut before the removals";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.write(b"e").unwrap(), 9, "{}", title);
+
+        // truncate_after(5) crashed once segment 1 is cut
+        let position = Position {
+            sequence: 1,
+            read: 2,
+        };
+        Cursor::truncation(&dir, position).save().unwrap();
+        wal.segments[1].truncate(2).unwrap();
+        drop(wal);
+        assert_segment_exists(&dir, &[0, 1, 2], title);
+
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert!(!dir.join(Segment::filename(2)).exists(), "{}", title);
+        assert!(!dir.join("truncation").exists(), "{}", title);
+        assert_eq!(wal.last_lsn(), Some(5), "{}", title);
+        assert_eq!(wal.get(5).unwrap().unwrap(), b"a", "{}", title);
+    }
+}
+
+#[test]
+fn test_truncate_stale_token() {
+    let cfg = Config {
+        entry_per_segment: 10,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_truncate_stale_token");
+    let dir = testhome.dir();
+    let mut wal = WAL::open(&dir, cfg).unwrap();
+    for i in 0..5 {
+        wal.write(&[i]).unwrap();
+    }
+
+    {
+        let title = "token taken before the truncation";
+        let (_, token) = wal.read_pending(5).unwrap();
+        let (_, consumer_token) = wal
+            .open_consumer("follower")
+            .unwrap()
+            .read_pending(5)
+            .unwrap();
+        wal.truncate_after(1).unwrap();
+
+        let err = wal.commit(token).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", title);
+        let mut consumer = wal.open_consumer("follower").unwrap();
+        let err = consumer.commit(consumer_token).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", title);
+
+        assert_eq!(wal.len(), 2, "{}", title);
+        assert_eq!(wal.write(&[9]).unwrap(), 2, "{}", title);
+        assert_eq!(
+            wal.read(3).unwrap(),
+            vec![vec![0], vec![1], vec![9]],
+            "{}",
+            title
+        );
+    }
+
+    {
+        let title = "token past the end of the segment";
+        let (_, mut token) = wal.read_pending(0).unwrap();
+        token.position.read = 10;
+        let err = wal.commit(token).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound, "{}", title);
+        assert!(wal.is_empty(), "{}", title);
+    }
+}
+
+#[test]
+fn test_damaged_sealed_segment() {
+    let per: usize = 4;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_damaged_sealed_segment");
+    let dir = testhome.dir();
+    let data = random_bytes(20);
+    let buf = data.as_slice();
+
+    let mut wal = WAL::open(&dir, cfg).unwrap();
+    for i in 0..10 {
+        wal.write(&buf[..i + 1]).unwrap();
+    }
+    drop(wal);
+
+    // a flipped byte in the last overhead of segment 0 looks like a torn tail
+    let path = dir.join(Segment::filename(0));
+    let f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let offset = 64 + 3 * 32 + 10;
+    let mut byte = [0; 1];
+    fileext::read_exact_at(&f, &mut byte, offset).unwrap();
+    fileext::write_all_at(&f, &[byte[0] ^ 0xff], offset).unwrap();
+    drop(f);
+
+    let wal = WAL::open(&dir, cfg).unwrap();
+    assert_segment_exists(&dir, &[0, 1, 2], "later segments are kept");
+    assert_eq!(wal.len(), 9);
+    assert!(wal.get(3).unwrap().is_none());
+    for lsn in 4..10 {
+        assert_eq!(wal.get(lsn).unwrap().unwrap(), &buf[..lsn as usize + 1]);
+    }
+}
+
+#[test]
+fn test_legacy_upgrade() {
+    let per: usize = 4;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_legacy_upgrade");
+    let dir = testhome.dir();
+    let data = random_bytes(20);
+    let buf = data.as_slice();
+
+    // the legacy segments before the last one are consumed already
+    write_legacy(&dir, 0, per, &[&buf[..1], &buf[..2]]);
+    let mut segment = Segment::open(&dir, 1, per, 10, true).unwrap();
+    segment.write(&buf[..3]).unwrap();
+    drop(segment);
+
+    {
+        let title = "segments after a legacy one are kept";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_segment_exists(&dir, &[0, 1], title);
+        assert_eq!(wal.len(), 3, "{}", title);
+        assert_eq!(wal.get(10).unwrap().unwrap(), &buf[..3], "{}", title);
+        assert_eq!(wal.write(&buf[..4]).unwrap(), 11, "{}", title);
+    }
+
+    {
+        let title = "legacy lsns derived from the next segment";
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.first_lsn(), Some(8), "{}", title);
+        assert_eq!(wal.get(8).unwrap().unwrap(), &buf[..1], "{}", title);
+        assert_eq!(wal.get(9).unwrap().unwrap(), &buf[..2], "{}", title);
+    }
+
+    {
+        let title = "legacy lsns stable once older segments are gone";
+        let dir = dir.join("legacy_only");
+        fs::create_dir_all(&dir).unwrap();
+        write_legacy(&dir, 0, per, &[&buf[..1], &buf[..2]]);
+        write_legacy(&dir, 1, per, &[&buf[..3], &buf[..4], &buf[..5]]);
+
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_segment_exists(&dir, &[0, 1, 2], title);
+        assert_eq!(wal.get(2).unwrap().unwrap(), &buf[..3], "{}", title);
+        wal.read(3).unwrap();
+        assert!(!dir.join(Segment::filename(0)).exists(), "{}", title);
+        drop(wal);
+
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.first_lsn(), Some(2), "{}", title);
+        assert_eq!(wal.get(3).unwrap().unwrap(), &buf[..4], "{}", title);
+        assert_eq!(wal.write(&buf[..6]).unwrap(), 5, "{}", title);
+    }
+}
+
+#[test]
+fn test_seek_rewind() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_seek_rewind");
+    let dir = testhome.dir();
+    let entry_num: usize = 35;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+
+    {
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        for i in 0..entry_num {
+            wal.write(&buf[..i + 1]).unwrap();
+        }
+        wal.read(per + 5).unwrap();
+    }
+
+    {
+        let title = "rewind";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        wal.rewind().unwrap();
+        assert_eq!(wal.len(), entry_num - per, "{}", title);
+        assert_eq!(wal.read(1).unwrap()[0], &buf[..per + 1], "{}", title);
+    }
+
+    {
+        let title = "seek by lsn";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.len(), entry_num - per - 1, "{}", title);
+
+        wal.seek(13).unwrap();
+        assert_eq!(wal.len(), entry_num - 13, "{}", title);
+        assert_eq!(wal.read(1).unwrap()[0], &buf[..14], "{}", title);
+
+        assert!(wal.seek(5).is_err(), "{}", title);
+        assert!(wal.seek(entry_num as u64 + 1).is_err(), "{}", title);
+    }
+
+    {
+        let title = "seek by position";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.len(), entry_num - 14, "{}", title);
+
+        wal.seek(Position {
+            sequence: 2,
+            read: 3,
+        })
+        .unwrap();
+        assert_eq!(wal.len(), entry_num - 2 * per - 3, "{}", title);
+        assert_eq!(wal.first_lsn(), Some(2 * per as u64), "{}", title);
+
+        let pos = Position {
+            sequence: 1,
+            read: 0,
+        };
+        assert!(wal.seek(pos).is_err(), "{}", title);
+        let pos = Position {
+            sequence: 2,
+            read: 11,
+        };
+        assert!(wal.seek(pos).is_err(), "{}", title);
+    }
+
+    {
+        let title = "seek to the end";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.len(), entry_num - 2 * per - 3, "{}", title);
+
+        wal.seek(entry_num as u64).unwrap();
+        assert!(wal.is_empty(), "{}", title);
+        assert_eq!(wal.segments.len(), 1, "{}", title);
+        assert_eq!(wal.first_lsn(), Some(3 * per as u64), "{}", title);
+    }
+}
+
+#[test]
+fn test_consumers() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_consumers");
+    let dir = testhome.dir();
+    let entry_num: usize = 35;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+
+    {
+        let title = "consumers read independently";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        for i in 0..entry_num {
+            wal.write(&buf[..i + 1]).unwrap();
+        }
+
+        {
+            let mut consumer = wal.open_consumer("indexer").unwrap();
+            assert_eq!(consumer.name(), "indexer", "{}", title);
+            assert_eq!(consumer.len(), entry_num, "{}", title);
+            assert_eq!(
+                consumer.read(2 * per + 5).unwrap().len(),
+                2 * per + 5,
+                "{}",
+                title
+            );
+            assert_eq!(consumer.len(), entry_num - 2 * per - 5, "{}", title);
+        }
+
+        // the default cursor holds no segment until it is used
+        assert!(!dir.join("cursor").exists(), "{}", title);
+        assert!(dir.join("cursor.indexer").exists(), "{}", title);
+        assert_eq!(wal.segments.len(), 2, "{}", title);
+        assert!(!dir.join(Segment::filename(1)).exists(), "{}", title);
+        assert_eq!(wal.len(), entry_num - 2 * per, "{}", title);
+
+        assert_eq!(wal.read(1).unwrap()[0], &buf[..2 * per + 1], "{}", title);
+        assert!(dir.join("cursor").exists(), "{}", title);
+        assert_segment_exists(&dir, &[2, 3], title);
+
+        assert!(wal.open_consumer("bad.name").is_err(), "{}", title);
+        assert!(wal.open_consumer("").is_err(), "{}", title);
+    }
+
+    {
+        let title = "consumers survive reopen";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.consumers(), vec!["indexer".to_owned()], "{}", title);
+        assert_eq!(wal.len(), entry_num - 2 * per - 1, "{}", title);
+
+        let mut consumer = wal.open_consumer("indexer").unwrap();
+        assert_eq!(
+            consumer.position(),
+            &Position {
+                sequence: 2,
+                read: 5
+            },
+            "{}",
+            title
+        );
+        assert_eq!(
+            consumer.peek(1).unwrap()[0],
+            &buf[..2 * per + 6],
+            "{}",
+            title
+        );
+
+        consumer.rewind().unwrap();
+        assert_eq!(consumer.len(), entry_num - 2 * per, "{}", title);
+        consumer.seek(entry_num as u64).unwrap();
+        assert!(consumer.is_empty(), "{}", title);
+    }
+
+    {
+        let title = "segments are released by the slowest consumer";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        assert_eq!(wal.segments.len(), 2, "{}", title);
+
+        wal.read(entry_num).unwrap();
+        assert_eq!(wal.segments.len(), 1, "{}", title);
+        assert_segment_exists(&dir, &[3], title);
+    }
+
+    {
+        let title = "remove consumer";
+        let mut wal = WAL::open(&dir, cfg).unwrap();
+        wal.open_consumer("replica").unwrap();
+        for _ in 0..per {
+            wal.write(&buf[..1]).unwrap();
+        }
+
+        wal.read(per).unwrap();
+        assert_eq!(wal.segments.len(), 2, "{}", title);
+
+        wal.remove_consumer("replica").unwrap();
+        assert_eq!(wal.consumers(), vec!["indexer".to_owned()], "{}", title);
+        assert!(!dir.join("cursor.replica").exists(), "{}", title);
+        assert_eq!(wal.segments.len(), 2, "{}", title);
+
+        wal.open_consumer("indexer").unwrap().read(per).unwrap();
+        assert_eq!(wal.segments.len(), 1, "{}", title);
+    }
+
+    {
+        let title = "an unused default cursor holds no segment";
+        let testhome = Home::new("testdir_wal_consumers_only");
+        let dir = testhome.dir();
+        {
+            let mut wal = WAL::open(&dir, cfg).unwrap();
+            for i in 0..entry_num {
+                wal.write(&buf[..i + 1]).unwrap();
+            }
+            wal.open_consumer("indexer")
+                .unwrap()
+                .read(entry_num)
+                .unwrap();
+            assert_segment_exists(&dir, &[3], title);
+        }
+
+        let wal = WAL::open(&dir, cfg).unwrap();
+        assert!(!dir.join("cursor").exists(), "{}", title);
+        assert_eq!(wal.segments.len(), 1, "{}", title);
+        assert_eq!(wal.len(), entry_num - 3 * per, "{}", title);
+    }
+}
+
+#[test]
+fn test_read_timeout() {
+    let testhome = Home::new("testdir_wal_read_timeout");
+    let cfg = Config {
+        entry_per_segment: 10,
+        ..Default::default()
+    };
+    let shared = SharedWAL::open(&testhome.dir(), cfg).unwrap();
+
+    {
+        let title = "timeout";
+        let start = Instant::now();
+        let out = shared.read_timeout(10, Duration::from_millis(50)).unwrap();
+        assert!(out.is_empty(), "{}", title);
+        assert!(start.elapsed() >= Duration::from_millis(50), "{}", title);
+    }
+
+    {
+        let title = "woken up by write";
+        let reader = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.read_timeout(10, Duration::from_secs(10)).unwrap())
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        shared.lock().write(b"plain").unwrap();
+
+        let out = reader.join().unwrap();
+        assert_eq!(out, vec![b"plain".to_vec()], "{}", title);
+    }
+
+    {
+        let title = "woken up by group commit";
+        let reader = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.read_timeout(10, Duration::from_secs(10)).unwrap())
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        shared.write(b"group").unwrap();
+
+        let out = reader.join().unwrap();
+        assert_eq!(out, vec![b"group".to_vec()], "{}", title);
+    }
+
+    {
+        let title = "entries available";
+        shared
+            .lock()
+            .batch_write(&[&b"a"[..], &b"b"[..], &b"c"[..]])
+            .unwrap();
+        let out = shared.read_timeout(2, Duration::from_secs(10)).unwrap();
+        assert_eq!(out.len(), 2, "{}", title);
+    }
+}
+
+#[test]
+fn test_map_sealed() {
+    let per: usize = 10;
+    let cfg = Config {
+        entry_per_segment: per,
+        check_crc32: true,
+        ..Default::default()
+    };
+
+    let testhome = Home::new("testdir_wal_map_sealed");
+    let entry_num: usize = 25;
+    let data = random_bytes(entry_num);
+    let buf = data.as_slice();
+    let mut wal = WAL::open(&testhome.dir(), cfg).unwrap();
+
+    for i in 0..entry_num {
+        wal.write(&buf[..i + 1]).unwrap();
+    }
+
+    {
+        let title = "sealed segments only";
+        let mapped = wal.map_sealed().unwrap();
+        assert_eq!(mapped.len(), 2, "{}", title);
+
+        for (n, segment) in mapped.iter().enumerate() {
+            assert_eq!(segment.first_lsn(), (n * per) as u64, "{}", title);
+            assert_eq!(segment.len(), per, "{}", title);
+
+            for entry in segment.iter() {
+                let (lsn, data) = entry.unwrap();
+                assert_eq!(data, &buf[..lsn as usize + 1], "{}", title);
+            }
+        }
+
+        assert!(mapped[0].get(per).is_err(), "{}", title);
+    }
+
+    {
+        let title = "full last segment";
+        for i in entry_num..per * 3 {
+            wal.write(&buf[..i % entry_num + 1]).unwrap();
+        }
+
+        let mapped = wal.map_sealed().unwrap();
+        assert_eq!(mapped.len(), 3, "{}", title);
+        assert_eq!(mapped[2].get(per - 1).unwrap(), &buf[..5], "{}", title);
+    }
+
+    {
+        let title = "corrupted entry";
+        let path = testhome.dir().join(Segment::filename(0));
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let mapped = wal.map_sealed().unwrap();
+        assert!(mapped[0].get(per - 1).is_err(), "{}", title);
+        assert!(mapped[0].get(per - 2).is_ok(), "{}", title);
+    }
+}
@@ -0,0 +1,76 @@
+use super::{EntryTooLarge, WAL};
+use crc::crc32::{Digest, Hasher32, IEEE};
+use std::io::{Result, Write};
+
+/// EntryWriter streams a single entry into the wal, without building it whole in memory.
+///
+/// The written bytes only become an entry once `finish` is called, dropping the writer
+/// before that discards them.
+pub struct EntryWriter<'a> {
+    wal: &'a mut WAL,
+    size: u64,
+    digest: Digest,
+    finished: bool,
+}
+
+impl<'a> EntryWriter<'a> {
+    pub(crate) fn new(wal: &'a mut WAL) -> Result<EntryWriter<'a>> {
+        // the size of the streamed entry is unknown, the limits are checked as if it is empty
+        wal.try_allocate(&[&[]])?;
+
+        Ok(EntryWriter {
+            wal,
+            size: 0,
+            digest: Digest::new(IEEE),
+            finished: false,
+        })
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns true if nothing is written yet.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Publishes the entry, returns its lsn.
+    pub fn finish(mut self) -> Result<u64> {
+        let segment = self.wal.segments.last_mut().unwrap();
+        let lsn = segment.next_lsn();
+        segment.publish(self.size, self.digest.sum32(), 0)?;
+        self.finished = true;
+
+        self.wal.after_write(1)?;
+        Ok(lsn)
+    }
+}
+
+impl<'a> Write for EntryWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let size = self.size as usize + buf.len();
+        EntryTooLarge::check(size, self.wal.cfg.max_entry_size)?;
+
+        let segment = self.wal.segments.last_mut().unwrap();
+        segment.write_pending(buf, self.size)?;
+
+        self.digest.write(buf);
+        self.size += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Drop for EntryWriter<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.wal.segments.last_mut().unwrap().discard_pending();
+        }
+    }
+}